@@ -0,0 +1,7 @@
+//! Short code generation, link storage, input rules and export files,
+//! shared by the hyperurl server and the `shorten` client's local mode.
+
+pub mod shortener;
+pub mod store;
+pub mod transfer;
+pub mod validate;
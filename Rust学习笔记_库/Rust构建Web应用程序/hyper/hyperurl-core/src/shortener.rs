@@ -0,0 +1,503 @@
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chrono::{DateTime, Duration, Utc};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use log::{debug, warn};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::Deserialize;
+
+use crate::store::{Link, LinkFilter, UrlStore};
+use crate::validate::{AliasPolicy, InvalidAlias};
+
+const MAX_ATTEMPTS: u32 = 16;
+/// Links read per store page while seeding the counter.
+const SEED_PAGE: usize = 1000;
+const BASE62: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+pub trait CodeGenerator: Send + Sync {
+    /// Proposes a code for `url`; `attempt` counts the collisions already
+    /// hit for this URL, so deterministic schemes can move past them.
+    fn generate(&self, url: &str, attempt: u32) -> String;
+
+    /// True when no code is ever proposed twice. Taken codes then cost no
+    /// attempt: walking past them always ends, however many there are.
+    fn unique(&self) -> bool {
+        false
+    }
+}
+
+/// Hex prefix of the URL's SHA-256, one character longer per collision.
+pub struct HashGenerator {
+    len: usize,
+}
+
+impl HashGenerator {
+    pub fn new(len: usize) -> Self {
+        HashGenerator { len }
+    }
+}
+
+impl CodeGenerator for HashGenerator {
+    fn generate(&self, url: &str, attempt: u32) -> String {
+        let mut sha = Sha256::new();
+        sha.input_str(url);
+        let len = self.len + attempt as usize;
+        if len > sha.output_bits() / 4 {
+            // Out of digest to grow into, so salt the input instead.
+            sha.input_str(&format!("#{}", attempt));
+        }
+        let mut s = sha.result_str();
+        s.truncate(len);
+        s
+    }
+}
+
+/// Sequential base62 codes, left-padded with `0` to `len` characters.
+pub struct CounterGenerator {
+    next: AtomicU64,
+    len: usize,
+}
+
+impl CounterGenerator {
+    pub fn starting_at(next: u64, len: usize) -> Self {
+        CounterGenerator { next: AtomicU64::new(next), len }
+    }
+
+    /// Starts right after the highest code in `db` this scheme could have
+    /// produced, wherever deletes and imports left the others.
+    pub fn after_codes_in(db: &dyn UrlStore, len: usize) -> Self {
+        let mut next = 0;
+        let mut after = None;
+        loop {
+            let page = db.page(after.as_deref(), SEED_PAGE, &LinkFilter::default());
+            let Some((last, _)) = page.last() else { break };
+            after = Some(last.clone());
+            for (code, _) in &page {
+                if let Some(n) = parse_base62(code).filter(|&n| padded_base62(n, len) == *code) {
+                    next = next.max(n.saturating_add(1));
+                }
+            }
+        }
+        CounterGenerator::starting_at(next, len)
+    }
+}
+
+impl CodeGenerator for CounterGenerator {
+    fn generate(&self, _url: &str, _attempt: u32) -> String {
+        padded_base62(self.next.fetch_add(1, Ordering::Relaxed), self.len)
+    }
+
+    /// Codes taken above the seed, such as custom aliases that happen to
+    /// look like counter codes, are skipped once and never proposed again.
+    fn unique(&self) -> bool {
+        true
+    }
+}
+
+/// Random base62 codes.
+pub struct RandomGenerator {
+    len: usize,
+}
+
+impl RandomGenerator {
+    pub fn new(len: usize) -> Self {
+        RandomGenerator { len }
+    }
+}
+
+impl CodeGenerator for RandomGenerator {
+    fn generate(&self, _url: &str, _attempt: u32) -> String {
+        rand::thread_rng()
+            .sample_iter(Alphanumeric)
+            .take(self.len)
+            .map(char::from)
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum CodeScheme {
+    Hash,
+    Counter,
+    Random,
+}
+
+impl CodeScheme {
+    /// Codes of `len` characters for links stored in `db`; the counter
+    /// scheme picks up after the codes already there.
+    pub fn generator(self, len: usize, db: &dyn UrlStore) -> Box<dyn CodeGenerator> {
+        match self {
+            CodeScheme::Hash => Box::new(HashGenerator::new(len)),
+            CodeScheme::Counter => Box::new(CounterGenerator::after_codes_in(db, len)),
+            CodeScheme::Random => Box::new(RandomGenerator::new(len)),
+        }
+    }
+}
+
+impl FromStr for CodeScheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hash" => Ok(CodeScheme::Hash),
+            "counter" => Ok(CodeScheme::Counter),
+            "random" => Ok(CodeScheme::Random),
+            _ => Err(format!("unknown code scheme `{}`, expected hash, counter or random", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for CodeScheme {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for CodeScheme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            CodeScheme::Hash => "hash",
+            CodeScheme::Counter => "counter",
+            CodeScheme::Random => "random",
+        })
+    }
+}
+
+pub struct Shortened {
+    pub code: String,
+    pub link: Link,
+    /// False when the URL already had a code.
+    pub created: bool,
+    /// Generated codes that turned out to be taken before one fit.
+    pub collisions: u32,
+}
+
+/// Whether a request for `wanted` may be answered with `existing`. Only
+/// permanent links of the same owner are shared; every expiring link gets
+/// its own code.
+pub fn reusable(existing: &Link, wanted: &Link) -> bool {
+    existing.url == wanted.url
+        && existing.owner == wanted.owner
+        && existing.expires_at.is_none()
+        && wanted.expires_at.is_none()
+}
+
+/// Finds or creates a code for `link`, reusing an existing one when the
+/// owner shortened the URL before and skipping codes already taken by other
+/// URLs or reserved by `aliases`.
+pub fn shorten_url(
+    db: &dyn UrlStore,
+    codes: &dyn CodeGenerator,
+    aliases: &AliasPolicy,
+    link: Link,
+) -> io::Result<Shortened> {
+    if link.expires_at.is_none() {
+        if let Some(code) = db.code_for(&link.url, link.owner.as_deref()) {
+            if let Some(existing) = db.get(&code) {
+                return Ok(Shortened { code, link: existing, created: false, collisions: 0 });
+            }
+        }
+    }
+    let mut collisions = 0;
+    let mut attempt = 0;
+    while attempt < MAX_ATTEMPTS {
+        let code = codes.generate(&link.url, attempt);
+        if !codes.unique() {
+            attempt += 1;
+        }
+        if aliases.is_reserved(&code) {
+            debug!("skipping reserved code `{}`", code);
+            continue;
+        }
+        match db.insert_if_absent(code.clone(), link.clone())? {
+            None => return Ok(Shortened { code, link, created: true, collisions }),
+            Some(existing) if reusable(&existing, &link) => {
+                return Ok(Shortened { code, link: existing, created: false, collisions })
+            }
+            Some(existing) if existing.url == link.url => {
+                debug!("`{}` is taken by another link to the same url", code)
+            }
+            Some(_) => warn!("short code collision on `{}`", code),
+        }
+        collisions += 1;
+    }
+    Err(io::Error::other("no free short code after repeated collisions"))
+}
+
+/// Why a custom alias could not be claimed.
+#[derive(Debug)]
+pub enum ClaimError {
+    Invalid(InvalidAlias),
+    /// The alias holds another link.
+    Taken(String),
+    Store(io::Error),
+}
+
+impl fmt::Display for ClaimError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClaimError::Invalid(e) => write!(f, "{}", e),
+            ClaimError::Taken(alias) => write!(f, "alias `{}` is already taken", alias),
+            ClaimError::Store(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for ClaimError {
+    fn from(e: io::Error) -> Self {
+        ClaimError::Store(e)
+    }
+}
+
+/// Stores `link` under the caller's own `alias`. Asking again for a link
+/// `reusable` would share hands back the one already there.
+pub fn claim_alias(db: &dyn UrlStore, aliases: &AliasPolicy, alias: String, link: Link) -> Result<Shortened, ClaimError> {
+    aliases.check(&alias).map_err(ClaimError::Invalid)?;
+    match db.insert_if_absent(alias.clone(), link.clone())? {
+        None => Ok(Shortened { code: alias, link, created: true, collisions: 0 }),
+        Some(existing) if reusable(&existing, &link) => {
+            Ok(Shortened { code: alias, link: existing, created: false, collisions: 0 })
+        }
+        Some(_) => Err(ClaimError::Taken(alias)),
+    }
+}
+
+/// Why a requested expiry was refused.
+#[derive(Debug, PartialEq)]
+pub enum InvalidExpiry {
+    /// Both `ttl` and `expires_at` were given.
+    Both,
+    TtlTooLarge(u64),
+    Past,
+}
+
+impl fmt::Display for InvalidExpiry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvalidExpiry::Both => write!(f, "give either ttl or expires_at, not both"),
+            InvalidExpiry::TtlTooLarge(ttl) => write!(f, "ttl {} is too large", ttl),
+            InvalidExpiry::Past => write!(f, "expiry must be in the future"),
+        }
+    }
+}
+
+/// When a link asking to live `ttl` seconds from `now`, or until
+/// `expires_at`, expires; `None` for a permanent one.
+pub fn expiry(
+    ttl: Option<u64>,
+    expires_at: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+) -> Result<Option<DateTime<Utc>>, InvalidExpiry> {
+    let at = match (ttl, expires_at) {
+        (Some(_), Some(_)) => return Err(InvalidExpiry::Both),
+        (Some(ttl), None) => i64::try_from(ttl)
+            .ok()
+            .and_then(Duration::try_seconds)
+            .and_then(|ttl| now.checked_add_signed(ttl))
+            .ok_or(InvalidExpiry::TtlTooLarge(ttl))?,
+        (None, Some(at)) => at,
+        (None, None) => return Ok(None),
+    };
+    if at <= now {
+        return Err(InvalidExpiry::Past);
+    }
+    Ok(Some(at))
+}
+
+/// `n` in base62, with leading zeros up to `len` characters.
+fn padded_base62(mut n: u64, len: usize) -> String {
+    let mut out = Vec::new();
+    loop {
+        out.push(BASE62[(n % 62) as usize]);
+        n /= 62;
+        if n == 0 {
+            break;
+        }
+    }
+    out.resize(out.len().max(len), b'0');
+    out.reverse();
+    String::from_utf8(out).unwrap()
+}
+
+/// The number `code` spells in base62, if it does and fits.
+fn parse_base62(code: &str) -> Option<u64> {
+    code.bytes().try_fold(0u64, |n, c| {
+        let digit = BASE62.iter().position(|&d| d == c)?;
+        n.checked_mul(62)?.checked_add(digit as u64)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    #[test]
+    fn same_url_gets_same_code() {
+        let db = MemoryStore::new();
+        let aliases = AliasPolicy::default();
+        for scheme in &[CodeScheme::Hash, CodeScheme::Counter, CodeScheme::Random] {
+            let codes = scheme.generator(5, &db);
+            let link = Link::new(format!("https://example.com/{}", scheme));
+            let first = shorten_url(&db, &*codes, &aliases, link.clone()).unwrap();
+            assert!(first.created);
+            let again = shorten_url(&db, &*codes, &aliases, link).unwrap();
+            assert!(!again.created);
+            assert_eq!(again.code, first.code);
+        }
+    }
+
+    #[test]
+    fn hash_collision_grows_code() {
+        let db = MemoryStore::new();
+        let aliases = AliasPolicy::default();
+        let codes = HashGenerator::new(5);
+        let url = "https://example.com/";
+        let taken = codes.generate(url, 0);
+        db.insert(taken.clone(), Link::new("https://other.example/".into())).unwrap();
+
+        let shortened = shorten_url(&db, &codes, &aliases, Link::new(url.into())).unwrap();
+        assert_eq!(shortened.collisions, 1);
+        let code = shortened.code;
+        assert_eq!(code.len(), 6);
+        assert!(code.starts_with(&taken));
+        assert_eq!(db.get(&taken).unwrap().url, "https://other.example/");
+        assert_eq!(shorten_url(&db, &codes, &aliases, Link::new(url.into())).unwrap().code, code);
+    }
+
+    #[test]
+    fn hash_salts_past_digest_length() {
+        let codes = HashGenerator::new(64);
+        assert_eq!(codes.generate("https://example.com/", 0).len(), 64);
+        assert_ne!(codes.generate("https://example.com/", 1), codes.generate("https://example.com/", 2));
+    }
+
+    #[test]
+    fn counter_skips_taken_codes() {
+        let db = MemoryStore::new();
+        let aliases = AliasPolicy::default();
+        db.insert("0".into(), Link::new("https://other.example/".into())).unwrap();
+        let codes = CounterGenerator::starting_at(0, 1);
+        let link = Link::new("https://example.com/".into());
+        assert_eq!(shorten_url(&db, &codes, &aliases, link).unwrap().code, "1");
+    }
+
+    #[test]
+    fn counter_walks_past_a_stale_seed() {
+        let db = MemoryStore::new();
+        let aliases = AliasPolicy::default();
+        // As when aliases take codes the counter had yet to reach.
+        for n in 0..100 {
+            db.insert(padded_base62(n, 5), Link::new(format!("https://example.com/{}", n))).unwrap();
+        }
+        let codes = CounterGenerator::starting_at(3, 5);
+        let link = Link::new("https://new.example/".into());
+        let shortened = shorten_url(&db, &codes, &aliases, link).unwrap();
+        assert_eq!(shortened.code, padded_base62(100, 5));
+        assert_eq!(shortened.collisions, 97);
+        let next = shorten_url(&db, &codes, &aliases, Link::new("https://next.example/".into())).unwrap();
+        assert_eq!((next.code, next.collisions), (padded_base62(101, 5), 0));
+    }
+
+    #[test]
+    fn counter_resumes_after_the_highest_code() {
+        let db = MemoryStore::new();
+        let aliases = AliasPolicy::default();
+        for code in ["00000", "00007", "0000a", "docs", "0001", "my-link"] {
+            db.insert(code.into(), Link::new(format!("https://example.com/{}", code))).unwrap();
+        }
+        let codes = CodeScheme::Counter.generator(5, &db);
+        let link = Link::new("https://new.example/".into());
+        let shortened = shorten_url(&db, &*codes, &aliases, link).unwrap();
+        assert_eq!((shortened.code.as_str(), shortened.collisions), ("0000b", 0));
+    }
+
+    #[test]
+    fn expiring_links_get_their_own_code() {
+        let db = MemoryStore::new();
+        let aliases = AliasPolicy::default();
+        let codes = HashGenerator::new(5);
+        let permanent = Link::new("https://example.com/".into());
+        let soon = Some(chrono::Utc::now() + chrono::Duration::hours(1));
+
+        let expiring = shorten_url(&db, &codes, &aliases, permanent.clone().expiring(soon)).unwrap();
+        let first = shorten_url(&db, &codes, &aliases, permanent.clone()).unwrap();
+        assert!(first.created);
+        assert_ne!(first.code, expiring.code);
+        assert_eq!(shorten_url(&db, &codes, &aliases, permanent.clone()).unwrap().code, first.code);
+        let again = shorten_url(&db, &codes, &aliases, permanent.expiring(soon)).unwrap();
+        assert_ne!(again.code, expiring.code);
+    }
+
+    #[test]
+    fn owners_do_not_share_codes() {
+        let db = MemoryStore::new();
+        let aliases = AliasPolicy::default();
+        let codes = RandomGenerator::new(7);
+        let link = Link::new("https://example.com/".into());
+
+        let alice = shorten_url(&db, &codes, &aliases, link.clone().owned_by(Some("alice".into()))).unwrap();
+        let bob = shorten_url(&db, &codes, &aliases, link.clone().owned_by(Some("bob".into()))).unwrap();
+        assert_ne!(alice.code, bob.code);
+        let again = shorten_url(&db, &codes, &aliases, link.owned_by(Some("bob".into()))).unwrap();
+        assert_eq!(again.code, bob.code);
+    }
+
+    #[test]
+    fn generated_codes_avoid_reserved_words() {
+        struct Fixed;
+        impl CodeGenerator for Fixed {
+            fn generate(&self, _url: &str, attempt: u32) -> String {
+                ["stats", "admin", "xyz12"][attempt as usize].to_string()
+            }
+        }
+        let db = MemoryStore::new();
+        let link = Link::new("https://example.com/".into());
+        let code = shorten_url(&db, &Fixed, &AliasPolicy::default(), link).unwrap();
+        assert_eq!(code.code, "xyz12");
+    }
+
+    #[test]
+    fn aliases_are_claimed_once() {
+        let db = MemoryStore::new();
+        let aliases = AliasPolicy::default();
+        let link = Link::new("https://example.com/".into());
+        assert!(claim_alias(&db, &aliases, "docs".into(), link.clone()).unwrap().created);
+        assert!(!claim_alias(&db, &aliases, "docs".into(), link).unwrap().created);
+        let other = Link::new("https://example.org/".into());
+        assert!(matches!(claim_alias(&db, &aliases, "docs".into(), other.clone()), Err(ClaimError::Taken(_))));
+        assert!(matches!(claim_alias(&db, &aliases, "api".into(), other), Err(ClaimError::Invalid(_))));
+    }
+
+    #[test]
+    fn expiry_rules() {
+        let now = Utc::now();
+        let later = now + Duration::seconds(60);
+        assert_eq!(expiry(None, None, now), Ok(None));
+        assert_eq!(expiry(Some(60), None, now), Ok(Some(later)));
+        assert_eq!(expiry(None, Some(later), now), Ok(Some(later)));
+        assert_eq!(expiry(Some(60), Some(later), now), Err(InvalidExpiry::Both));
+        assert_eq!(expiry(None, Some(now), now), Err(InvalidExpiry::Past));
+        assert_eq!(expiry(Some(u64::MAX), None, now), Err(InvalidExpiry::TtlTooLarge(u64::MAX)));
+    }
+
+    #[test]
+    fn base62_encoding() {
+        assert_eq!(padded_base62(0, 1), "0");
+        assert_eq!(padded_base62(61, 1), "Z");
+        assert_eq!(padded_base62(62, 1), "10");
+        assert_eq!(padded_base62(62, 5), "00010");
+        assert_eq!(padded_base62(62 * 62 * 62, 2), "1000");
+        assert_eq!(parse_base62("00010"), Some(62));
+        assert_eq!(parse_base62("my-link"), None);
+        assert_eq!(parse_base62(&"Z".repeat(12)), None);
+    }
+}
@@ -0,0 +1,485 @@
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::{Index, Link, LinkFilter, UrlStore};
+
+const COMPACT_AFTER: usize = 4096;
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Record {
+    Remove { remove: String },
+    Put {
+        code: String,
+        #[serde(flatten)]
+        link: Link,
+    },
+}
+
+struct Wal {
+    file: File,
+    entries: usize,
+    /// A failed append left bytes that could not be cut off again; nothing
+    /// is appended after them until a compaction starts a fresh log.
+    torn: bool,
+}
+
+/// Append-only store: every change goes to `<path>.wal` before it is
+/// applied in memory, and the log is folded into the snapshot at `<path>`
+/// once it grows past `COMPACT_AFTER` entries. Only one process at a time
+/// may have it open, which an exclusive lock on `<path>.lock` enforces.
+pub struct FileStore {
+    snapshot: PathBuf,
+    wal_path: PathBuf,
+    index: RwLock<Index>,
+    wal: Mutex<Wal>,
+    /// Held until the store is dropped.
+    _lock: File,
+}
+
+impl FileStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let snapshot = path.as_ref().to_path_buf();
+        let mut wal_path = snapshot.clone().into_os_string();
+        wal_path.push(".wal");
+        let wal_path = PathBuf::from(wal_path);
+        // Taken before reading anything, so no other writer can be halfway
+        // through an append or a compaction.
+        let lock = lock(&snapshot)?;
+
+        let mut index = Index::default();
+        // Snapshots are renamed into place whole, so any bad line in one is
+        // damage; only the log can end in a torn append.
+        replay(&snapshot, &mut index, false)?;
+        let (entries, torn) = replay(&wal_path, &mut index, true)?;
+
+        let file = OpenOptions::new().create(true).append(true).open(&wal_path)?;
+        let store = FileStore {
+            snapshot,
+            wal_path,
+            index: RwLock::new(index),
+            wal: Mutex::new(Wal { file, entries, torn: false }),
+            _lock: lock,
+        };
+        // Never append after a damaged record; start from a clean log instead.
+        if torn {
+            store.compact()?;
+        }
+        Ok(store)
+    }
+
+    /// Writes the current map to a fresh snapshot and truncates the log.
+    fn compact_locked(&self, wal: &mut Wal) -> io::Result<()> {
+        self.write_snapshot(&self.snapshot)?;
+        wal.file = File::create(&self.wal_path)?;
+        wal.entries = 0;
+        wal.torn = false;
+        Ok(())
+    }
+
+    /// Atomically replaces `path` with every current link. Callers hold the
+    /// log lock so no write can slip in between.
+    fn write_snapshot(&self, path: &Path) -> io::Result<()> {
+        let mut tmp = path.to_path_buf().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+
+        let mut out = BufWriter::new(File::create(&tmp)?);
+        for (code, link) in self.index.read().unwrap().links.iter() {
+            write_record(&mut out, &Record::Put { code: code.clone(), link: link.clone() })?;
+        }
+        out.into_inner()?.sync_all()?;
+        fs::rename(&tmp, path)
+    }
+
+    fn sibling(&self, suffix: &str) -> PathBuf {
+        let mut path = self.snapshot.clone().into_os_string();
+        path.push(suffix);
+        PathBuf::from(path)
+    }
+
+    /// Logs `records`, then applies them in memory. A failed write is cut
+    /// off the log again, since replay refuses damage before its last line.
+    fn append(&self, wal: &mut Wal, records: Vec<Record>) -> io::Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        if wal.torn {
+            self.compact_locked(wal)?;
+        }
+        let mut buf = Vec::new();
+        for record in &records {
+            write_record(&mut buf, record)?;
+        }
+        let len = wal.file.metadata()?.len();
+        if let Err(e) = wal.file.write_all(&buf).and_then(|()| wal.file.sync_data()) {
+            if let Err(undo) = wal.file.set_len(len) {
+                warn!("cannot truncate {} after a failed write: {}", self.wal_path.display(), undo);
+                wal.torn = true;
+            }
+            return Err(e);
+        }
+        wal.entries += records.len();
+
+        let mut index = self.index.write().unwrap();
+        for record in records {
+            apply(&mut index, record);
+        }
+        drop(index);
+
+        // The records are durable by now; a failed compaction is retried on
+        // the next write rather than reported as a failed one.
+        if wal.entries >= COMPACT_AFTER {
+            if let Err(e) = self.compact_locked(wal) {
+                warn!("compacting {} failed: {}", self.snapshot.display(), e);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl UrlStore for FileStore {
+    fn get(&self, code: &str) -> Option<Link> {
+        self.index.read().unwrap().get(code)
+    }
+
+    fn code_for(&self, url: &str, owner: Option<&str>) -> Option<String> {
+        self.index.read().unwrap().code_for(url, owner)
+    }
+
+    fn insert(&self, code: String, link: Link) -> io::Result<()> {
+        let mut wal = self.wal.lock().unwrap();
+        self.append(&mut wal, vec![Record::Put { code, link }])
+    }
+
+    fn insert_if_absent(&self, code: String, link: Link) -> io::Result<Option<Link>> {
+        // Writers are serialized by the log lock, so the check cannot go stale.
+        let mut wal = self.wal.lock().unwrap();
+        if let Some(existing) = self.get(&code) {
+            return Ok(Some(existing));
+        }
+        self.append(&mut wal, vec![Record::Put { code, link }])?;
+        Ok(None)
+    }
+
+    fn remove(&self, code: &str) -> io::Result<Option<Link>> {
+        let mut wal = self.wal.lock().unwrap();
+        let old = self.get(code);
+        if old.is_some() {
+            self.append(&mut wal, vec![Record::Remove { remove: code.to_string() }])?;
+        }
+        Ok(old)
+    }
+
+    fn compare_and_swap(&self, code: &str, expected: &Link, new: Option<Link>) -> io::Result<bool> {
+        let mut wal = self.wal.lock().unwrap();
+        if self.get(code).as_ref() != Some(expected) {
+            return Ok(false);
+        }
+        let record = match new {
+            Some(link) => Record::Put { code: code.to_string(), link },
+            None => Record::Remove { remove: code.to_string() },
+        };
+        self.append(&mut wal, vec![record])?;
+        Ok(true)
+    }
+
+    fn page(&self, after: Option<&str>, limit: usize, filter: &LinkFilter) -> Vec<(String, Link)> {
+        self.index.read().unwrap().page(after, limit, filter)
+    }
+
+    fn remove_expired(&self, now: DateTime<Utc>) -> io::Result<Vec<String>> {
+        let mut wal = self.wal.lock().unwrap();
+        let expired = self.index.read().unwrap().expired(now);
+        let records = expired.iter().map(|code| Record::Remove { remove: code.clone() }).collect();
+        self.append(&mut wal, records)?;
+        Ok(expired)
+    }
+
+    fn len(&self) -> usize {
+        self.index.read().unwrap().len()
+    }
+
+    /// Creates and removes a file next to the snapshot, which compaction
+    /// needs to be able to do.
+    fn check(&self) -> io::Result<()> {
+        let _wal = self.wal.lock().unwrap();
+        let probe = self.sibling(".probe");
+        File::create(&probe)?.sync_all()?;
+        fs::remove_file(&probe)
+    }
+
+    fn compact(&self) -> io::Result<bool> {
+        let mut wal = self.wal.lock().unwrap();
+        self.compact_locked(&mut wal)?;
+        Ok(true)
+    }
+
+    /// Copies the current links to `<path>.<UTC timestamp>`, which can be
+    /// opened as a store of its own.
+    fn snapshot(&self) -> io::Result<Option<PathBuf>> {
+        let _wal = self.wal.lock().unwrap();
+        let path = self.sibling(&Utc::now().format(".%Y%m%dT%H%M%S%.3fZ").to_string());
+        self.write_snapshot(&path)?;
+        Ok(Some(path))
+    }
+}
+
+fn write_record<W: Write>(out: &mut W, record: &Record) -> io::Result<()> {
+    serde_json::to_writer(&mut *out, record)?;
+    out.write_all(b"\n")
+}
+
+fn apply(index: &mut Index, record: Record) {
+    match record {
+        Record::Put { code, link } => index.insert(code, link),
+        Record::Remove { remove } => {
+            index.remove(&remove);
+        }
+    }
+}
+
+/// Locks `<path>.lock`, failing at once when another process holds it.
+fn lock(path: &Path) -> io::Result<File> {
+    let mut lock_path = path.to_path_buf().into_os_string();
+    lock_path.push(".lock");
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open(&lock_path)?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            format!("{} is in use by another process", path.display()),
+        )),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+/// Loads every record of `path` into `index`, returning how many were read
+/// and whether a torn last line was skipped. That is only allowed with
+/// `torn_tail`; any other bad record fails, since dropping it would lose
+/// links for good at the next compaction.
+fn replay(path: &Path, index: &mut Index, torn_tail: bool) -> io::Result<(usize, bool)> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok((0, false)),
+        Err(e) => return Err(e),
+    };
+
+    let mut entries = 0;
+    let mut lines = BufReader::new(file).lines().enumerate().peekable();
+    while let Some((n, line)) = lines.next() {
+        let line = line?;
+        match serde_json::from_str::<Record>(&line) {
+            Ok(record) => {
+                apply(index, record);
+                entries += 1;
+            }
+            // A crash in the middle of an append leaves a torn last line.
+            Err(e) if torn_tail && lines.peek().is_none() => {
+                warn!("{}:{}: skipping torn last record: {}", path.display(), n + 1, e);
+                return Ok((entries, true));
+            }
+            Err(e) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}:{}: bad record: {}", path.display(), n + 1, e),
+                ))
+            }
+        }
+    }
+    Ok((entries, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(url: &str) -> Link {
+        Link::new(url.to_string())
+    }
+
+    #[test]
+    fn failed_writes_leave_the_log_readable() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("links.db");
+
+        let store = FileStore::open(&path).unwrap();
+        store.insert("abcde".into(), link("https://example.com/")).unwrap();
+        // A read-only handle fails both the write and the truncation.
+        store.wal.lock().unwrap().file = File::open(&store.wal_path).unwrap();
+        assert!(store.insert("fghij".into(), link("https://example.org/")).is_err());
+        assert!(store.get("fghij").is_none());
+        store.insert("klmno".into(), link("https://example.net/")).unwrap();
+        drop(store);
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.len(), 2);
+        assert!(store.get("klmno").is_some());
+    }
+
+    #[test]
+    fn one_opener_at_a_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("links.db");
+
+        let store = FileStore::open(&path).unwrap();
+        let err = FileStore::open(&path).err().expect("the store is locked");
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert!(err.to_string().contains("in use"), "{}", err);
+        drop(store);
+        assert!(FileStore::open(&path).is_ok());
+    }
+
+    #[test]
+    fn keeps_creation_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("links.db");
+
+        let store = FileStore::open(&path).unwrap();
+        let created = link("https://example.com/");
+        store.insert("abcde".into(), created.clone()).unwrap();
+        drop(store);
+
+        assert_eq!(FileStore::open(&path).unwrap().get("abcde"), Some(created));
+    }
+
+    #[test]
+    fn replays_log_after_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("links.db");
+
+        let store = FileStore::open(&path).unwrap();
+        store.insert("abcde".into(), link("https://example.com/")).unwrap();
+        store.insert("fghij".into(), link("https://example.org/")).unwrap();
+        drop(store);
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get("abcde").unwrap().url, "https://example.com/");
+    }
+
+    #[test]
+    fn compaction_keeps_mappings() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("links.db");
+
+        let store = FileStore::open(&path).unwrap();
+        store.insert("abcde".into(), link("https://example.com/")).unwrap();
+        store.compact().unwrap();
+        store.insert("fghij".into(), link("https://example.org/")).unwrap();
+        drop(store);
+
+        assert_eq!(fs::read_to_string(dir.path().join("links.db.wal")).unwrap().lines().count(), 1);
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get("fghij").unwrap().url, "https://example.org/");
+    }
+
+    #[test]
+    fn removals_survive_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("links.db");
+        let now = Utc::now();
+
+        let store = FileStore::open(&path).unwrap();
+        store.insert("abcde".into(), link("https://example.com/")).unwrap();
+        store.insert("fghij".into(), link("https://example.org/").expiring(Some(now))).unwrap();
+        store.insert("klmno".into(), link("https://example.net/")).unwrap();
+        assert_eq!(store.remove_expired(now).unwrap(), ["fghij"]);
+        assert!(store.remove("klmno").unwrap().is_some());
+        drop(store);
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.len(), 1);
+        assert!(store.get("fghij").is_none());
+        assert!(store.code_for("https://example.net/", None).is_none());
+    }
+
+    #[test]
+    fn swaps_only_unchanged_links() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("links.db");
+
+        let store = FileStore::open(&path).unwrap();
+        let old = link("https://example.com/");
+        store.insert("abcde".into(), old.clone()).unwrap();
+        let new = link("https://example.org/");
+        assert!(store.compare_and_swap("abcde", &old, Some(new.clone())).unwrap());
+        assert!(!store.compare_and_swap("abcde", &old, None).unwrap());
+        drop(store);
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.get("abcde"), Some(new.clone()));
+        assert!(store.code_for("https://example.com/", None).is_none());
+        assert!(store.compare_and_swap("abcde", &new, None).unwrap());
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn snapshots_open_as_stores() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("links.db");
+
+        let store = FileStore::open(&path).unwrap();
+        store.check().unwrap();
+        store.insert("abcde".into(), link("https://example.com/")).unwrap();
+        let copy = store.snapshot().unwrap().unwrap();
+        store.insert("fghij".into(), link("https://example.org/")).unwrap();
+
+        assert_eq!(copy.parent(), Some(dir.path()));
+        let copy = FileStore::open(&copy).unwrap();
+        assert_eq!(copy.len(), 1);
+        assert!(copy.get("abcde").is_some());
+        assert!(!dir.path().join("links.db.probe").exists());
+    }
+
+    #[test]
+    fn skips_torn_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("links.db");
+
+        let store = FileStore::open(&path).unwrap();
+        store.insert("abcde".into(), link("https://example.com/")).unwrap();
+        drop(store);
+        let mut wal = OpenOptions::new().append(true).open(dir.path().join("links.db.wal")).unwrap();
+        wal.write_all(b"{\"code\":\"fgh").unwrap();
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.len(), 1);
+        store.insert("fghij".into(), link("https://example.org/")).unwrap();
+        drop(store);
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.len(), 2);
+    }
+
+    #[test]
+    fn refuses_damage_before_the_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("links.db");
+        let wal_path = dir.path().join("links.db.wal");
+
+        let store = FileStore::open(&path).unwrap();
+        store.insert("abcde".into(), link("https://example.com/")).unwrap();
+        store.insert("fghij".into(), link("https://example.org/")).unwrap();
+        drop(store);
+        let log = fs::read_to_string(&wal_path).unwrap();
+        fs::write(&wal_path, log.replacen("{\"code\"", "{\"cod", 1)).unwrap();
+
+        let err = FileStore::open(&path).err().expect("interior damage is refused");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("links.db.wal:1"), "{}", err);
+        // Nothing was compacted away in the attempt.
+        assert_eq!(fs::read_to_string(&wal_path).unwrap().lines().count(), 2);
+
+        fs::write(&wal_path, "").unwrap();
+        fs::write(&path, "{\"code\":\"abcde\",\"url\":\"https://example.com/\"}\n{\"cod").unwrap();
+        assert!(FileStore::open(&path).is_err());
+    }
+}
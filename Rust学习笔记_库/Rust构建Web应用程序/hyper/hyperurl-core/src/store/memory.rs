@@ -0,0 +1,194 @@
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::hash::BuildHasher;
+use std::io;
+use std::sync::RwLock;
+
+use chrono::{DateTime, Utc};
+
+use super::{expired, page, Link, LinkFilter, UrlIndex, UrlStore};
+
+const SHARDS: usize = 16;
+
+type Links = BTreeMap<String, Link>;
+
+/// Links sharded by code and the reverse index sharded by (owner, URL), each
+/// shard behind its own lock, so requests for different codes rarely wait on
+/// each other. A link shard is always locked before a URL shard, never the
+/// other way round.
+pub struct MemoryStore {
+    links: Vec<RwLock<Links>>,
+    urls: Vec<RwLock<UrlIndex>>,
+    hasher: RandomState,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        MemoryStore {
+            links: (0..SHARDS).map(|_| RwLock::default()).collect(),
+            urls: (0..SHARDS).map(|_| RwLock::default()).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn links(&self, code: &str) -> &RwLock<Links> {
+        &self.links[self.hasher.hash_one(code) as usize % SHARDS]
+    }
+
+    fn urls(&self, url: &str, owner: Option<&str>) -> &RwLock<UrlIndex> {
+        &self.urls[self.hasher.hash_one((owner, url)) as usize % SHARDS]
+    }
+
+    /// Stores `link` in the already locked shard `links`, keeping the
+    /// reverse index in step.
+    fn put(&self, links: &mut Links, code: String, link: Link) {
+        if let Some(old) = links.get(&code) {
+            self.urls(&old.url, old.owner.as_deref()).write().unwrap().remove(&code, old);
+        }
+        self.urls(&link.url, link.owner.as_deref()).write().unwrap().add(&code, &link);
+        links.insert(code, link);
+    }
+
+    fn take(&self, links: &mut Links, code: &str) -> Option<Link> {
+        let old = links.remove(code)?;
+        self.urls(&old.url, old.owner.as_deref()).write().unwrap().remove(code, &old);
+        Some(old)
+    }
+}
+
+impl UrlStore for MemoryStore {
+    fn get(&self, code: &str) -> Option<Link> {
+        self.links(code).read().unwrap().get(code).cloned()
+    }
+
+    fn code_for(&self, url: &str, owner: Option<&str>) -> Option<String> {
+        self.urls(url, owner).read().unwrap().get(url, owner)
+    }
+
+    fn insert(&self, code: String, link: Link) -> io::Result<()> {
+        let mut links = self.links(&code).write().unwrap();
+        self.put(&mut links, code, link);
+        Ok(())
+    }
+
+    fn insert_if_absent(&self, code: String, link: Link) -> io::Result<Option<Link>> {
+        let mut links = self.links(&code).write().unwrap();
+        if let Some(existing) = links.get(&code) {
+            return Ok(Some(existing.clone()));
+        }
+        self.put(&mut links, code, link);
+        Ok(None)
+    }
+
+    fn remove(&self, code: &str) -> io::Result<Option<Link>> {
+        let mut links = self.links(code).write().unwrap();
+        Ok(self.take(&mut links, code))
+    }
+
+    fn compare_and_swap(&self, code: &str, expected: &Link, new: Option<Link>) -> io::Result<bool> {
+        let mut links = self.links(code).write().unwrap();
+        if links.get(code) != Some(expected) {
+            return Ok(false);
+        }
+        match new {
+            Some(link) => self.put(&mut links, code.to_string(), link),
+            None => {
+                self.take(&mut links, code);
+            }
+        }
+        Ok(true)
+    }
+
+    fn page(&self, after: Option<&str>, limit: usize, filter: &LinkFilter) -> Vec<(String, Link)> {
+        let mut found: Vec<_> = self
+            .links
+            .iter()
+            .flat_map(|shard| page(&shard.read().unwrap(), after, limit, filter))
+            .collect();
+        found.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        found.truncate(limit);
+        found
+    }
+
+    fn remove_expired(&self, now: DateTime<Utc>) -> io::Result<Vec<String>> {
+        let mut removed = Vec::new();
+        for shard in &self.links {
+            let mut links = shard.write().unwrap();
+            for code in expired(&links, now) {
+                self.take(&mut links, &code);
+                removed.push(code);
+            }
+        }
+        Ok(removed)
+    }
+
+    fn len(&self) -> usize {
+        self.links.iter().map(|shard| shard.read().unwrap().len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn pages_are_ordered_across_shards() {
+        let db = MemoryStore::new();
+        for n in 0..100 {
+            db.insert(format!("c{:03}", n), Link::new(format!("https://example.com/{}", n))).unwrap();
+        }
+        let first = db.page(None, 10, &LinkFilter::default());
+        let codes: Vec<_> = first.iter().map(|(c, _)| c.as_str()).collect();
+        assert_eq!(codes, (0..10).map(|n| format!("c{:03}", n)).collect::<Vec<_>>());
+        let next = db.page(Some("c009"), 1, &LinkFilter::default());
+        assert_eq!(next[0].0, "c010");
+        assert_eq!(db.len(), 100);
+    }
+
+    #[test]
+    fn reverse_index_follows_changes() {
+        let db = MemoryStore::new();
+        let old = Link::new("https://example.com/".into());
+        db.insert("abc".into(), old.clone()).unwrap();
+        assert_eq!(db.code_for("https://example.com/", None).as_deref(), Some("abc"));
+
+        let new = Link::new("https://example.org/".into());
+        assert!(db.compare_and_swap("abc", &old, Some(new)).unwrap());
+        assert!(db.code_for("https://example.com/", None).is_none());
+        assert_eq!(db.code_for("https://example.org/", None).as_deref(), Some("abc"));
+
+        db.remove("abc").unwrap();
+        assert!(db.code_for("https://example.org/", None).is_none());
+    }
+
+    #[test]
+    fn concurrent_writers_keep_every_link() {
+        let db = Arc::new(MemoryStore::new());
+        let writers: Vec<_> = (0..8)
+            .map(|t| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    for n in 0..500 {
+                        let code = format!("{}-{}", t, n);
+                        let url = format!("https://example.com/{}", code);
+                        db.insert_if_absent(code.clone(), Link::new(url.clone())).unwrap();
+                        assert_eq!(db.code_for(&url, None), Some(code));
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(db.len(), 4000);
+        assert!(db.remove_expired(Utc::now()).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,314 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+mod file;
+mod memory;
+
+pub use self::file::FileStore;
+pub use self::memory::MemoryStore;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Link {
+    pub url: String,
+    // Logs written before links carried metadata get stamped on replay.
+    #[serde(default = "Utc::now")]
+    pub created_at: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// API key owner that created the link; `None` for anonymous links.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+}
+
+impl Link {
+    pub fn new(url: String) -> Self {
+        Link { url, created_at: Utc::now(), expires_at: None, owner: None }
+    }
+
+    pub fn owned_by(mut self, owner: Option<String>) -> Self {
+        self.owner = owner;
+        self
+    }
+
+    pub fn expiring(mut self, at: Option<DateTime<Utc>>) -> Self {
+        self.expires_at = at;
+        self
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+}
+
+pub trait UrlStore: Send + Sync {
+    fn get(&self, code: &str) -> Option<Link>;
+    /// Finds a code `owner` already has pointing at `url` that never expires.
+    fn code_for(&self, url: &str, owner: Option<&str>) -> Option<String>;
+    fn insert(&self, code: String, link: Link) -> io::Result<()>;
+    /// Stores `link` under `code` unless the code is taken, in which case the
+    /// link already there is returned and nothing is written.
+    fn insert_if_absent(&self, code: String, link: Link) -> io::Result<Option<Link>>;
+    fn remove(&self, code: &str) -> io::Result<Option<Link>>;
+    /// Replaces the link under `code` with `new`, or removes it when `new`
+    /// is `None`, but only while it still equals `expected`. Returns whether
+    /// the swap happened.
+    fn compare_and_swap(&self, code: &str, expected: &Link, new: Option<Link>) -> io::Result<bool>;
+    /// Up to `limit` links matching `filter`, in code order, starting after
+    /// the code `after`.
+    fn page(&self, after: Option<&str>, limit: usize, filter: &LinkFilter) -> Vec<(String, Link)>;
+    /// Drops every link that expired at or before `now`, returning their
+    /// codes.
+    fn remove_expired(&self, now: DateTime<Utc>) -> io::Result<Vec<String>>;
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Fails if the store could not take writes right now.
+    fn check(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Folds any log into the main data file. `false` when the backend has
+    /// nothing of the sort.
+    fn compact(&self) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    /// Writes a consistent copy of every link, returning where, or `None`
+    /// when the backend cannot.
+    fn snapshot(&self) -> io::Result<Option<PathBuf>> {
+        Ok(None)
+    }
+}
+
+/// Narrows a listing down to one owner and/or codes sharing a prefix.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LinkFilter {
+    pub owner: Option<String>,
+    pub prefix: Option<String>,
+}
+
+impl LinkFilter {
+    pub fn matches(&self, code: &str, link: &Link) -> bool {
+        self.prefix.as_ref().is_none_or(|p| code.starts_with(p.as_str()))
+            && self.owner.as_ref().is_none_or(|o| link.owner.as_ref() == Some(o))
+    }
+}
+
+type OwnedUrl = (Option<String>, String);
+
+/// (owner, URL) → code reverse index, shared by the backends. Expiring
+/// links stay out of it so that shortening a URL for good never hands back
+/// a code that is about to vanish. Every code of a URL is kept, oldest
+/// first, so removing the one handed out falls back to the next.
+#[derive(Default)]
+struct UrlIndex {
+    codes: HashMap<OwnedUrl, Vec<String>>,
+}
+
+impl UrlIndex {
+    fn get(&self, url: &str, owner: Option<&str>) -> Option<String> {
+        self.codes.get(&(owner.map(str::to_string), url.to_string()))?.first().cloned()
+    }
+
+    fn add(&mut self, code: &str, link: &Link) {
+        if link.expires_at.is_none() {
+            let codes = self.codes.entry(owned_url(link)).or_default();
+            if !codes.iter().any(|c| c == code) {
+                codes.push(code.to_string());
+            }
+        }
+    }
+
+    fn remove(&mut self, code: &str, old: &Link) {
+        let key = owned_url(old);
+        if let Some(codes) = self.codes.get_mut(&key) {
+            codes.retain(|c| c != code);
+            if codes.is_empty() {
+                self.codes.remove(&key);
+            }
+        }
+    }
+}
+
+/// Code → link map plus its reverse index under one lock. Codes are kept
+/// sorted so listings can resume from a cursor.
+#[derive(Default)]
+struct Index {
+    links: BTreeMap<String, Link>,
+    codes: UrlIndex,
+}
+
+impl Index {
+    fn get(&self, code: &str) -> Option<Link> {
+        self.links.get(code).cloned()
+    }
+
+    fn code_for(&self, url: &str, owner: Option<&str>) -> Option<String> {
+        self.codes.get(url, owner)
+    }
+
+    fn insert(&mut self, code: String, link: Link) {
+        if let Some(old) = self.links.get(&code) {
+            self.codes.remove(&code, old);
+        }
+        self.codes.add(&code, &link);
+        self.links.insert(code, link);
+    }
+
+    fn remove(&mut self, code: &str) -> Option<Link> {
+        let old = self.links.remove(code)?;
+        self.codes.remove(code, &old);
+        Some(old)
+    }
+
+    fn page(&self, after: Option<&str>, limit: usize, filter: &LinkFilter) -> Vec<(String, Link)> {
+        page(&self.links, after, limit, filter)
+    }
+
+    fn expired(&self, now: DateTime<Utc>) -> Vec<String> {
+        expired(&self.links, now)
+    }
+
+    fn len(&self) -> usize {
+        self.links.len()
+    }
+}
+
+fn page(
+    links: &BTreeMap<String, Link>,
+    after: Option<&str>,
+    limit: usize,
+    filter: &LinkFilter,
+) -> Vec<(String, Link)> {
+    let prefix = filter.prefix.as_deref().unwrap_or("");
+    // Start from whichever of the cursor and the prefix sorts later.
+    let start = match after {
+        Some(after) if after >= prefix => Bound::Excluded(after),
+        _ => Bound::Included(prefix),
+    };
+    links
+        .range::<str, _>((start, Bound::Unbounded))
+        .take_while(|(code, _)| code.starts_with(prefix))
+        .filter(|(code, link)| filter.matches(code, link))
+        .take(limit)
+        .map(|(code, link)| (code.clone(), link.clone()))
+        .collect()
+}
+
+fn expired(links: &BTreeMap<String, Link>, now: DateTime<Utc>) -> Vec<String> {
+    links
+        .iter()
+        .filter(|(_, link)| link.is_expired(now))
+        .map(|(code, _)| code.clone())
+        .collect()
+}
+
+fn owned_url(link: &Link) -> OwnedUrl {
+    (link.owner.clone(), link.url.clone())
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum StoreConfig {
+    Memory,
+    File(PathBuf),
+}
+
+impl StoreConfig {
+    pub fn open(&self) -> io::Result<Arc<dyn UrlStore>> {
+        Ok(match self {
+            StoreConfig::Memory => Arc::new(MemoryStore::new()),
+            StoreConfig::File(path) => Arc::new(FileStore::open(path)?),
+        })
+    }
+}
+
+impl FromStr for StoreConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(StoreConfig::Memory),
+            _ => match s.strip_prefix("file:") {
+                Some(path) if !path.is_empty() => Ok(StoreConfig::File(path.into())),
+                _ => Err(format!("unknown store `{}`, expected `memory` or `file:<path>`", s)),
+            },
+        }
+    }
+}
+
+impl TryFrom<String> for StoreConfig {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for StoreConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoreConfig::Memory => write!(f, "memory"),
+            StoreConfig::File(path) => write!(f, "file:{}", path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_resume_after_cursor() {
+        let mut index = Index::default();
+        for (code, owner) in [("aa1", "ann"), ("ab2", "bob"), ("ab3", "ann"), ("ac4", "ann"), ("b5", "ann")] {
+            index.insert(code.into(), Link::new(format!("https://example.com/{}", code)).owned_by(Some(owner.into())));
+        }
+        let codes = |page: Vec<(String, Link)>| page.into_iter().map(|(c, _)| c).collect::<Vec<_>>();
+
+        let all = LinkFilter::default();
+        assert_eq!(codes(index.page(None, 2, &all)), ["aa1", "ab2"]);
+        assert_eq!(codes(index.page(Some("ab2"), 10, &all)), ["ab3", "ac4", "b5"]);
+
+        let ann = LinkFilter { owner: Some("ann".into()), prefix: Some("ab".into()) };
+        assert_eq!(codes(index.page(None, 10, &ann)), ["ab3"]);
+        assert_eq!(codes(index.page(Some("aa0"), 10, &ann)), ["ab3"]);
+        assert!(index.page(Some("ab3"), 10, &ann).is_empty());
+    }
+
+    #[test]
+    fn url_index_falls_back_to_surviving_codes() {
+        let link = Link::new("https://example.com/".into());
+        let mut index = UrlIndex::default();
+        index.add("abc", &link);
+        index.add("def", &link);
+        assert_eq!(index.get("https://example.com/", None).as_deref(), Some("abc"));
+
+        index.remove("xyz", &link);
+        assert_eq!(index.get("https://example.com/", None).as_deref(), Some("abc"));
+        index.remove("abc", &link);
+        assert_eq!(index.get("https://example.com/", None).as_deref(), Some("def"));
+        index.remove("def", &link);
+        assert!(index.get("https://example.com/", None).is_none());
+        assert!(index.codes.is_empty());
+    }
+
+    #[test]
+    fn parse_store_config() {
+        assert_eq!("memory".parse(), Ok(StoreConfig::Memory));
+        assert_eq!("file:links.db".parse(), Ok(StoreConfig::File("links.db".into())));
+        assert!("file:".parse::<StoreConfig>().is_err());
+        assert!("redis".parse::<StoreConfig>().is_err());
+    }
+}
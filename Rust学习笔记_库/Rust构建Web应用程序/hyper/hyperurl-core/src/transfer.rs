@@ -0,0 +1,308 @@
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::store::{Link, LinkFilter, UrlStore};
+use crate::validate::{AliasPolicy, UrlPolicy};
+
+/// Links read from the store per page while exporting.
+const EXPORT_PAGE: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Jsonl,
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jsonl" => Ok(Format::Jsonl),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("unknown format `{}`, expected jsonl or csv", s)),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Format::Jsonl => "jsonl",
+            Format::Csv => "csv",
+        })
+    }
+}
+
+/// One JSONL line: the code next to the link's own fields.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    code: String,
+    #[serde(flatten)]
+    link: Link,
+}
+
+/// CSV rows keep every column so the file stays rectangular; empty cells
+/// stand for missing values.
+#[derive(Serialize, Deserialize)]
+struct Row {
+    code: String,
+    url: String,
+    created_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    owner: Option<String>,
+}
+
+impl From<Row> for Entry {
+    fn from(row: Row) -> Self {
+        let link = Link { url: row.url, created_at: row.created_at, expires_at: row.expires_at, owner: row.owner };
+        Entry { code: row.code, link }
+    }
+}
+
+/// Writes every link in code order, returning how many.
+pub fn export<W: Write>(db: &dyn UrlStore, format: Format, out: W) -> io::Result<usize> {
+    let mut out = match format {
+        Format::Jsonl => Sink::Jsonl(io::BufWriter::new(out)),
+        Format::Csv => Sink::Csv(Box::new(csv::Writer::from_writer(out))),
+    };
+    let mut count = 0;
+    let mut cursor = None;
+    loop {
+        let page = db.page(cursor.as_deref(), EXPORT_PAGE, &LinkFilter::default());
+        let Some((last, _)) = page.last() else { break };
+        cursor = Some(last.clone());
+        for (code, link) in page {
+            out.write(Entry { code, link })?;
+            count += 1;
+        }
+    }
+    out.flush()?;
+    Ok(count)
+}
+
+enum Sink<W: Write> {
+    Jsonl(io::BufWriter<W>),
+    Csv(Box<csv::Writer<W>>),
+}
+
+impl<W: Write> Sink<W> {
+    fn write(&mut self, entry: Entry) -> io::Result<()> {
+        match self {
+            Sink::Jsonl(out) => {
+                serde_json::to_writer(&mut *out, &entry)?;
+                out.write_all(b"\n")
+            }
+            Sink::Csv(out) => Ok(out.serialize(Row {
+                code: entry.code,
+                url: entry.link.url,
+                created_at: entry.link.created_at,
+                expires_at: entry.link.expires_at,
+                owner: entry.link.owner,
+            })?),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Jsonl(out) => out.flush(),
+            Sink::Csv(out) => out.flush(),
+        }
+    }
+}
+
+/// What an import did. Problems are listed by input line and never stop
+/// the rest of the file from loading.
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportReport {
+    pub imported: usize,
+    /// Codes already holding the very same link.
+    pub unchanged: usize,
+    /// Codes holding a different link, left alone.
+    pub conflicts: Vec<(usize, String)>,
+    pub invalid: Vec<(usize, String)>,
+}
+
+impl ImportReport {
+    pub fn is_clean(&self) -> bool {
+        self.conflicts.is_empty() && self.invalid.is_empty()
+    }
+}
+
+/// Loads links keeping their codes and metadata. URLs go through `policy`
+/// like freshly shortened ones, and codes must be reachable under `aliases`;
+/// with `overwrite`, conflicting codes are replaced instead of reported.
+pub fn import<R: Read>(
+    db: &dyn UrlStore,
+    format: Format,
+    input: R,
+    policy: &UrlPolicy,
+    aliases: &AliasPolicy,
+    overwrite: bool,
+) -> io::Result<ImportReport> {
+    let mut report = ImportReport::default();
+    match format {
+        Format::Jsonl => {
+            for (n, line) in BufReader::new(input).lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let entry = serde_json::from_str(&line).map_err(|e| e.to_string());
+                report.add(db, n + 1, entry, policy, aliases, overwrite)?;
+            }
+        }
+        Format::Csv => {
+            let mut reader = csv::Reader::from_reader(input);
+            let headers = reader.headers()?.clone();
+            let mut record = csv::StringRecord::new();
+            loop {
+                let entry = match reader.read_record(&mut record) {
+                    Ok(false) => break,
+                    Ok(true) => record.deserialize::<Row>(Some(&headers)).map(Entry::from),
+                    Err(e) if e.is_io_error() => return Err(e.into()),
+                    Err(e) => Err(e),
+                };
+                let line = record.position().map_or(0, |p| p.line() as usize);
+                report.add(db, line, entry.map_err(|e| e.to_string()), policy, aliases, overwrite)?;
+            }
+        }
+    }
+    Ok(report)
+}
+
+impl ImportReport {
+    fn add(
+        &mut self,
+        db: &dyn UrlStore,
+        line: usize,
+        entry: Result<Entry, String>,
+        policy: &UrlPolicy,
+        aliases: &AliasPolicy,
+        overwrite: bool,
+    ) -> io::Result<()> {
+        let mut entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                self.invalid.push((line, e));
+                return Ok(());
+            }
+        };
+        if entry.code.is_empty() {
+            self.invalid.push((line, "empty code".to_string()));
+            return Ok(());
+        }
+        // Routes would shadow reserved words, and other characters do not
+        // survive a path segment unchanged.
+        if let Err(e) = aliases.check_code(&entry.code) {
+            self.invalid.push((line, format!("bad code `{}`: {}", entry.code, e)));
+            return Ok(());
+        }
+        entry.link.url = match policy.normalize(&entry.link.url) {
+            Ok(url) => url,
+            Err(e) => {
+                self.invalid.push((line, e.to_string()));
+                return Ok(());
+            }
+        };
+        match db.insert_if_absent(entry.code.clone(), entry.link.clone())? {
+            None => self.imported += 1,
+            Some(existing) if existing == entry.link => self.unchanged += 1,
+            Some(_) if overwrite => {
+                db.insert(entry.code, entry.link)?;
+                self.imported += 1;
+            }
+            Some(_) => self.conflicts.push((line, entry.code)),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    fn sample() -> MemoryStore {
+        let db = MemoryStore::new();
+        let at = "2024-05-01T12:00:00Z".parse().unwrap();
+        let link = |url: &str| Link { url: url.into(), created_at: at, expires_at: None, owner: None };
+        db.insert("abc".into(), link("https://example.com/a")).unwrap();
+        db.insert("xyz".into(), Link { expires_at: Some(at), owner: Some("ann".into()), ..link("https://example.com/x") }).unwrap();
+        db
+    }
+
+    #[test]
+    fn round_trips_both_formats() {
+        for format in [Format::Jsonl, Format::Csv] {
+            let mut out = Vec::new();
+            assert_eq!(export(&sample(), format, &mut out).unwrap(), 2);
+
+            let copy = MemoryStore::new();
+            let report = import(&copy, format, &out[..], &UrlPolicy::default(), &AliasPolicy::default(), false).unwrap();
+            assert_eq!(report.imported, 2, "{}", format);
+            assert!(report.is_clean());
+            assert_eq!(copy.get("xyz"), sample().get("xyz"));
+            assert_eq!(copy.get("abc"), sample().get("abc"));
+
+            let again = import(&copy, format, &out[..], &UrlPolicy::default(), &AliasPolicy::default(), false).unwrap();
+            assert_eq!(again.unchanged, 2);
+        }
+    }
+
+    #[test]
+    fn csv_has_every_column() {
+        let mut out = Vec::new();
+        export(&sample(), Format::Csv, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "code,url,created_at,expires_at,owner\n\
+             abc,https://example.com/a,2024-05-01T12:00:00Z,,\n\
+             xyz,https://example.com/x,2024-05-01T12:00:00Z,2024-05-01T12:00:00Z,ann\n"
+        );
+    }
+
+    #[test]
+    fn reports_bad_lines_and_conflicts() {
+        let db = sample();
+        let input = "{\"code\":\"abc\",\"url\":\"https://example.com/other\"}\n\
+                     not json\n\
+                     \n\
+                     {\"code\":\"new\",\"url\":\"ftp://example.com/\"}\n\
+                     {\"code\":\"ok\",\"url\":\"https://example.com/ok\"}\n";
+        let report = import(&db, Format::Jsonl, input.as_bytes(), &UrlPolicy::default(), &AliasPolicy::default(), false).unwrap();
+        assert_eq!(report.imported, 1);
+        assert_eq!(report.conflicts, [(1, "abc".to_string())]);
+        assert_eq!(report.invalid.iter().map(|(n, _)| *n).collect::<Vec<_>>(), [2, 4]);
+        assert_eq!(db.get("abc").unwrap().url, "https://example.com/a");
+
+        let report = import(&db, Format::Jsonl, input.as_bytes(), &UrlPolicy::default(), &AliasPolicy::default(), true).unwrap();
+        assert!(report.conflicts.is_empty());
+        assert_eq!(db.get("abc").unwrap().url, "https://example.com/other");
+
+        let csv = "code,url,created_at,expires_at,owner\nq,https://example.com/,yesterday,,\n";
+        let report = import(&db, Format::Csv, csv.as_bytes(), &UrlPolicy::default(), &AliasPolicy::default(), false).unwrap();
+        assert_eq!(report.invalid.len(), 1);
+        assert_eq!(report.invalid[0].0, 2);
+    }
+
+    #[test]
+    fn refuses_unreachable_codes() {
+        let db = MemoryStore::new();
+        let input = ["api", "Stats", "a/b", "", "-x", "café", "1", "x_y-2"]
+            .iter()
+            .map(|code| format!("{{\"code\":\"{}\",\"url\":\"https://example.com/{}\"}}\n", code, code.len()))
+            .collect::<String>();
+        let report =
+            import(&db, Format::Jsonl, input.as_bytes(), &UrlPolicy::default(), &AliasPolicy::default(), false)
+                .unwrap();
+        assert_eq!(report.invalid.iter().map(|(n, _)| *n).collect::<Vec<_>>(), [1, 2, 3, 4, 5, 6]);
+        assert!(report.invalid[0].1.contains("reserved"), "{:?}", report.invalid);
+        assert_eq!(report.imported, 2);
+        assert!(db.get("1").is_some() && db.get("x_y-2").is_some());
+    }
+}
@@ -0,0 +1,198 @@
+use std::fmt;
+
+use url::Url;
+
+/// Rules applied to every URL before it is shortened.
+#[derive(Debug, Clone)]
+pub struct UrlPolicy {
+    pub max_length: usize,
+    pub strip_fragment: bool,
+}
+
+impl Default for UrlPolicy {
+    fn default() -> Self {
+        UrlPolicy { max_length: 2048, strip_fragment: false }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum InvalidUrl {
+    Empty,
+    TooLong(usize),
+    Malformed(url::ParseError),
+    Scheme(String),
+    MissingHost,
+    Credentials,
+}
+
+impl fmt::Display for InvalidUrl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvalidUrl::Empty => write!(f, "url is empty"),
+            InvalidUrl::TooLong(max) => write!(f, "url is longer than {} bytes", max),
+            InvalidUrl::Malformed(e) => write!(f, "url is malformed: {}", e),
+            InvalidUrl::Scheme(scheme) => write!(f, "scheme `{}` is not allowed, use http or https", scheme),
+            InvalidUrl::MissingHost => write!(f, "url has no host"),
+            InvalidUrl::Credentials => write!(f, "url must not carry a username or password"),
+        }
+    }
+}
+
+impl UrlPolicy {
+    /// Parses `raw` and returns its canonical form: lowercase host, no
+    /// default port and, if configured, no fragment.
+    pub fn normalize(&self, raw: &str) -> Result<String, InvalidUrl> {
+        let raw = raw.trim();
+        if raw.is_empty() {
+            return Err(InvalidUrl::Empty);
+        }
+        if raw.len() > self.max_length {
+            return Err(InvalidUrl::TooLong(self.max_length));
+        }
+
+        let mut url = Url::parse(raw).map_err(InvalidUrl::Malformed)?;
+        match url.scheme() {
+            "http" | "https" => {}
+            other => return Err(InvalidUrl::Scheme(other.to_string())),
+        }
+        if url.host_str().is_none_or(str::is_empty) {
+            return Err(InvalidUrl::MissingHost);
+        }
+        if !url.username().is_empty() || url.password().is_some() {
+            return Err(InvalidUrl::Credentials);
+        }
+        // The parser already lowercases hosts and drops default ports for
+        // http(s); only the optional parts are left to us.
+        if self.strip_fragment {
+            url.set_fragment(None);
+        }
+
+        let normalized = String::from(url);
+        if normalized.len() > self.max_length {
+            return Err(InvalidUrl::TooLong(self.max_length));
+        }
+        Ok(normalized)
+    }
+}
+
+/// Top-level path segments the service routes itself, plus a few words
+/// kept back for later use. Neither aliases nor generated codes may take them.
+pub const RESERVED: &[&str] = &[
+    "admin", "api", "favicon.ico", "healthz", "login", "logout", "metrics", "qr", "readyz", "robots.txt",
+    "shorten", "static", "stats", "version",
+];
+
+/// Rules for user-chosen aliases.
+#[derive(Debug, Clone)]
+pub struct AliasPolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    /// Lowercase words that are never handed out.
+    pub reserved: Vec<String>,
+}
+
+impl Default for AliasPolicy {
+    fn default() -> Self {
+        AliasPolicy {
+            min_length: 3,
+            max_length: 64,
+            reserved: RESERVED.iter().map(|w| w.to_string()).collect(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum InvalidAlias {
+    Length { min: usize, max: usize },
+    Charset(char),
+    Reserved(String),
+}
+
+impl fmt::Display for InvalidAlias {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvalidAlias::Length { min, max } => write!(f, "alias must be {} to {} characters long", min, max),
+            InvalidAlias::Charset(c) => {
+                write!(f, "alias may only use letters, digits, `-` and `_` and must start with a letter or digit, not `{}`", c)
+            }
+            InvalidAlias::Reserved(word) => write!(f, "alias `{}` is reserved", word),
+        }
+    }
+}
+
+impl AliasPolicy {
+    pub fn check(&self, alias: &str) -> Result<(), InvalidAlias> {
+        let len = alias.chars().count();
+        if len < self.min_length || len > self.max_length {
+            return Err(InvalidAlias::Length { min: self.min_length, max: self.max_length });
+        }
+        self.check_code(alias)
+    }
+
+    /// The rules every reachable code meets, chosen or not: the alias
+    /// charset and no reserved word. Lengths are left out, as counter codes
+    /// start at one character and hash codes grow on collisions.
+    pub fn check_code(&self, code: &str) -> Result<(), InvalidAlias> {
+        for (i, c) in code.chars().enumerate() {
+            let allowed = c.is_ascii_alphanumeric() || (i > 0 && (c == '-' || c == '_'));
+            if !allowed {
+                return Err(InvalidAlias::Charset(c));
+            }
+        }
+        if self.is_reserved(code) {
+            return Err(InvalidAlias::Reserved(code.to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn is_reserved(&self, code: &str) -> bool {
+        let code = code.to_ascii_lowercase();
+        self.reserved.contains(&code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alias_policy() {
+        let policy = AliasPolicy::default();
+        assert_eq!(policy.check("team-standup"), Ok(()));
+        assert_eq!(policy.check("Q4_plan"), Ok(()));
+        assert_eq!(policy.check("ab"), Err(InvalidAlias::Length { min: 3, max: 64 }));
+        assert_eq!(policy.check(&"a".repeat(65)), Err(InvalidAlias::Length { min: 3, max: 64 }));
+        assert_eq!(policy.check("-team"), Err(InvalidAlias::Charset('-')));
+        assert_eq!(policy.check("team/standup"), Err(InvalidAlias::Charset('/')));
+        assert_eq!(policy.check("café"), Err(InvalidAlias::Charset('é')));
+        assert_eq!(policy.check("Stats"), Err(InvalidAlias::Reserved("Stats".into())));
+        assert_eq!(policy.check("admin"), Err(InvalidAlias::Reserved("admin".into())));
+    }
+
+    #[test]
+    fn normalizes_host_and_port() {
+        let policy = UrlPolicy::default();
+        assert_eq!(policy.normalize(" HTTP://Example.COM:80/Path?q=1 ").unwrap(), "http://example.com/Path?q=1");
+        assert_eq!(policy.normalize("https://example.com:443").unwrap(), "https://example.com/");
+        assert_eq!(policy.normalize("https://example.com:8443/").unwrap(), "https://example.com:8443/");
+    }
+
+    #[test]
+    fn fragments_are_optional() {
+        let keep = UrlPolicy::default();
+        let strip = UrlPolicy { strip_fragment: true, ..UrlPolicy::default() };
+        assert_eq!(keep.normalize("https://example.com/#top").unwrap(), "https://example.com/#top");
+        assert_eq!(strip.normalize("https://example.com/#top").unwrap(), "https://example.com/");
+    }
+
+    #[test]
+    fn rejects() {
+        let policy = UrlPolicy { max_length: 32, ..UrlPolicy::default() };
+        assert_eq!(policy.normalize("  "), Err(InvalidUrl::Empty));
+        assert_eq!(policy.normalize("ftp://example.com/"), Err(InvalidUrl::Scheme("ftp".into())));
+        assert_eq!(policy.normalize("javascript:alert(1)"), Err(InvalidUrl::Scheme("javascript".into())));
+        assert_eq!(policy.normalize("https://user:pw@example.com/"), Err(InvalidUrl::Credentials));
+        assert_eq!(policy.normalize("https://example.com/a-very-long-path"), Err(InvalidUrl::TooLong(32)));
+        assert!(matches!(policy.normalize("not a url"), Err(InvalidUrl::Malformed(_))));
+    }
+}
@@ -0,0 +1,180 @@
+//! Throughput of the in-memory store under a mixed create/redirect load,
+//! compared with the single `RwLock<HashMap>` it replaced.
+//!
+//!     cargo bench --bench store
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use hyperurl::store::{Link, LinkFilter, MemoryStore, UrlStore};
+
+const PRELOAD: u64 = 10_000;
+/// One operation in this many creates a link; the rest are redirects.
+const CREATE_EVERY: u64 = 10;
+
+/// The previous design: one lock around both maps.
+#[derive(Default)]
+struct GlobalLock {
+    inner: RwLock<Maps>,
+}
+
+/// Links plus the (owner, URL) → codes index `MemoryStore` keeps, updated
+/// the same way so both sides of the comparison do the same work.
+#[derive(Default)]
+struct Maps {
+    links: HashMap<String, Link>,
+    codes: HashMap<(Option<String>, String), Vec<String>>,
+}
+
+impl Maps {
+    fn insert(&mut self, code: String, link: Link) {
+        self.remove(&code);
+        if link.expires_at.is_none() {
+            let key = (link.owner.clone(), link.url.clone());
+            self.codes.entry(key).or_default().push(code.clone());
+        }
+        self.links.insert(code, link);
+    }
+
+    fn remove(&mut self, code: &str) -> Option<Link> {
+        let old = self.links.remove(code)?;
+        let key = (old.owner.clone(), old.url.clone());
+        if let Some(codes) = self.codes.get_mut(&key) {
+            codes.retain(|c| c != code);
+            if codes.is_empty() {
+                self.codes.remove(&key);
+            }
+        }
+        Some(old)
+    }
+}
+
+impl UrlStore for GlobalLock {
+    fn get(&self, code: &str) -> Option<Link> {
+        self.inner.read().unwrap().links.get(code).cloned()
+    }
+
+    fn code_for(&self, url: &str, owner: Option<&str>) -> Option<String> {
+        let inner = self.inner.read().unwrap();
+        inner.codes.get(&(owner.map(str::to_string), url.to_string()))?.first().cloned()
+    }
+
+    fn insert(&self, code: String, link: Link) -> io::Result<()> {
+        self.inner.write().unwrap().insert(code, link);
+        Ok(())
+    }
+
+    fn insert_if_absent(&self, code: String, link: Link) -> io::Result<Option<Link>> {
+        let mut inner = self.inner.write().unwrap();
+        if let Some(existing) = inner.links.get(&code) {
+            return Ok(Some(existing.clone()));
+        }
+        inner.insert(code, link);
+        Ok(None)
+    }
+
+    fn remove(&self, code: &str) -> io::Result<Option<Link>> {
+        Ok(self.inner.write().unwrap().remove(code))
+    }
+
+    fn compare_and_swap(&self, code: &str, expected: &Link, new: Option<Link>) -> io::Result<bool> {
+        let mut inner = self.inner.write().unwrap();
+        if inner.links.get(code) != Some(expected) {
+            return Ok(false);
+        }
+        match new {
+            Some(link) => inner.insert(code.to_string(), link),
+            None => {
+                inner.remove(code);
+            }
+        }
+        Ok(true)
+    }
+
+    fn page(&self, after: Option<&str>, limit: usize, filter: &LinkFilter) -> Vec<(String, Link)> {
+        let inner = self.inner.read().unwrap();
+        let sorted: BTreeMap<_, _> = inner.links.iter().collect();
+        sorted
+            .into_iter()
+            .filter(|(code, link)| after.is_none_or(|a| code.as_str() > a) && filter.matches(code, link))
+            .take(limit)
+            .map(|(code, link)| (code.clone(), link.clone()))
+            .collect()
+    }
+
+    fn remove_expired(&self, now: DateTime<Utc>) -> io::Result<Vec<String>> {
+        let mut inner = self.inner.write().unwrap();
+        let expired: Vec<String> = inner
+            .links
+            .iter()
+            .filter(|(_, link)| link.is_expired(now))
+            .map(|(code, _)| code.clone())
+            .collect();
+        for code in &expired {
+            inner.remove(code);
+        }
+        Ok(expired)
+    }
+
+    fn len(&self) -> usize {
+        self.inner.read().unwrap().links.len()
+    }
+}
+
+fn preloaded<S: UrlStore + Default>() -> Arc<S> {
+    let db = S::default();
+    for n in 0..PRELOAD {
+        db.insert(format!("c{}", n), Link::new(format!("https://example.com/{}", n))).unwrap();
+    }
+    Arc::new(db)
+}
+
+/// Runs `ops` operations split over `threads` and returns the wall time.
+fn mixed_load(db: &dyn UrlStore, threads: u64, ops: u64, fresh: &AtomicU64) -> Duration {
+    let start = Instant::now();
+    thread::scope(|s| {
+        for t in 0..threads {
+            s.spawn(move || {
+                for i in 0..ops / threads {
+                    if i % CREATE_EVERY == 0 {
+                        let n = fresh.fetch_add(1, Ordering::Relaxed);
+                        let url = format!("https://example.org/{}", n);
+                        if db.code_for(&url, None).is_none() {
+                            db.insert_if_absent(format!("n{}", n), Link::new(url)).unwrap();
+                        }
+                    } else {
+                        let code = format!("c{}", (i * 7919 + t * 104_729) % PRELOAD);
+                        assert!(db.get(&code).is_some());
+                    }
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+
+fn bench_stores(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mixed_load");
+    group.throughput(Throughput::Elements(1));
+    let stores: [(&str, Arc<dyn UrlStore>); 2] =
+        [("global_lock", preloaded::<GlobalLock>()), ("sharded", preloaded::<MemoryStore>())];
+    for threads in [1, 4, 8] {
+        for (name, db) in &stores {
+            let fresh = AtomicU64::new(0);
+            group.bench_with_input(BenchmarkId::new(*name, threads), &threads, |b, &threads| {
+                b.iter_custom(|iters| mixed_load(&**db, threads, iters.max(threads), &fresh))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_stores);
+criterion_main!(benches);
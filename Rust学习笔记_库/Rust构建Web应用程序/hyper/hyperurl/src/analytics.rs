@@ -0,0 +1,249 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use hyper::header::{REFERER, USER_AGENT};
+use hyper::HeaderMap;
+use log::warn;
+use rand::Rng;
+use serde::Serialize;
+
+const QUEUE: usize = 4096;
+const BATCH: usize = 256;
+const RECENT: usize = 20;
+const TOP_REFERRERS: usize = 10;
+const MAX_REFERRERS: usize = 1000;
+const OTHER_REFERRER: &str = "(other)";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Hit {
+    pub at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub referrer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    /// Salted hash of the client address; the address itself is never kept.
+    pub client: String,
+}
+
+#[derive(Default)]
+struct CodeStats {
+    total: u64,
+    daily: BTreeMap<NaiveDate, u64>,
+    referrers: HashMap<String, u64>,
+    recent: VecDeque<Hit>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DayCount {
+    pub date: NaiveDate,
+    pub clicks: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReferrerCount {
+    pub referrer: String,
+    pub clicks: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub code: String,
+    pub total_clicks: u64,
+    pub daily: Vec<DayCount>,
+    pub top_referrers: Vec<ReferrerCount>,
+    /// Last hits in full; only filled in for the link's owner.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recent: Option<Vec<Hit>>,
+}
+
+type StatsMap = Arc<Mutex<HashMap<String, CodeStats>>>;
+
+enum Event {
+    Hit(String, Hit),
+    Forget(String),
+}
+
+/// Click counters fed through a bounded queue. Redirects only pay for a
+/// `try_send`; a background thread folds hits into the per-code totals.
+/// Counters live in memory only and start over when the server restarts.
+pub struct Analytics {
+    hits: SyncSender<Event>,
+    stats: StatsMap,
+    salt: [u8; 16],
+}
+
+impl Default for Analytics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Analytics {
+    pub fn new() -> Self {
+        let (hits, queue) = mpsc::sync_channel(QUEUE);
+        let stats = StatsMap::default();
+        let aggregated = stats.clone();
+        thread::Builder::new()
+            .name("analytics".into())
+            .spawn(move || aggregate(queue, aggregated))
+            .expect("cannot start analytics thread");
+        Analytics { hits, stats, salt: rand::thread_rng().gen() }
+    }
+
+    pub fn record(&self, code: &str, headers: &HeaderMap, client: IpAddr) {
+        let header = |name| headers.get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+        let hit = Hit {
+            at: Utc::now(),
+            referrer: header(REFERER),
+            user_agent: header(USER_AGENT),
+            client: self.hash_client(client),
+        };
+        match self.hits.try_send(Event::Hit(code.to_string(), hit)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => warn!("analytics queue full, dropping hit on `{}`", code),
+            Err(TrySendError::Disconnected(_)) => warn!("analytics thread is gone"),
+        }
+    }
+
+    /// Totals for `code`, plus its recent hits when `recent` is set.
+    pub fn stats(&self, code: &str, recent: bool) -> Stats {
+        let map = self.stats.lock().unwrap();
+        let mut out = Stats {
+            code: code.to_string(),
+            total_clicks: 0,
+            daily: Vec::new(),
+            top_referrers: Vec::new(),
+            recent: if recent { Some(Vec::new()) } else { None },
+        };
+        if let Some(stats) = map.get(code) {
+            out.total_clicks = stats.total;
+            out.daily = stats.daily.iter().map(|(&date, &clicks)| DayCount { date, clicks }).collect();
+            let mut referrers: Vec<_> = stats.referrers.iter().collect();
+            referrers.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+            out.top_referrers = referrers
+                .into_iter()
+                .take(TOP_REFERRERS)
+                .map(|(referrer, &clicks)| ReferrerCount { referrer: referrer.clone(), clicks })
+                .collect();
+            if recent {
+                out.recent = Some(stats.recent.iter().cloned().collect());
+            }
+        }
+        out
+    }
+
+    /// Drops the counters of a deleted link. Goes through the queue, and
+    /// waits for room there, so hits queued before the delete go too.
+    pub fn forget(&self, code: &str) {
+        if self.hits.send(Event::Forget(code.to_string())).is_err() {
+            warn!("analytics thread is gone");
+        }
+    }
+
+    fn hash_client(&self, client: IpAddr) -> String {
+        let mut sha = Sha256::new();
+        sha.input(&self.salt);
+        sha.input_str(&client.to_string());
+        let mut s = sha.result_str();
+        s.truncate(16);
+        s
+    }
+}
+
+fn aggregate(queue: Receiver<Event>, stats: StatsMap) {
+    let mut batch = Vec::with_capacity(BATCH);
+    // Exits once every sender, i.e. the `Analytics`, is dropped.
+    while let Ok(first) = queue.recv() {
+        batch.push(first);
+        batch.extend(queue.try_iter().take(BATCH - 1));
+
+        let mut map = stats.lock().unwrap();
+        for event in batch.drain(..) {
+            match event {
+                Event::Hit(code, hit) => map.entry(code).or_default().add(hit),
+                Event::Forget(code) => {
+                    map.remove(&code);
+                }
+            }
+        }
+    }
+}
+
+impl CodeStats {
+    fn add(&mut self, hit: Hit) {
+        self.total += 1;
+        *self.daily.entry(hit.at.date_naive()).or_default() += 1;
+        if let Some(ref referrer) = hit.referrer {
+            let known = self.referrers.len() < MAX_REFERRERS || self.referrers.contains_key(referrer);
+            let key = if known { referrer.as_str() } else { OTHER_REFERRER };
+            *self.referrers.entry(key.to_string()).or_default() += 1;
+        }
+        if self.recent.len() == RECENT {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(hit);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn wait_for(analytics: &Analytics, code: &str, total: u64) -> Stats {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        loop {
+            let stats = analytics.stats(code, true);
+            if stats.total_clicks >= total || Instant::now() > deadline {
+                return stats;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn aggregates_hits() {
+        let analytics = Analytics::new();
+        let client: IpAddr = "192.0.2.7".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(REFERER, "https://news.example/".parse().unwrap());
+        analytics.record("abcde", &headers, client);
+        analytics.record("abcde", &headers, client);
+        analytics.record("abcde", &HeaderMap::new(), client);
+
+        let stats = wait_for(&analytics, "abcde", 3);
+        assert_eq!(stats.total_clicks, 3);
+        assert_eq!(stats.daily.len(), 1);
+        assert_eq!(stats.daily[0].clicks, 3);
+        assert_eq!(stats.top_referrers[0].referrer, "https://news.example/");
+        assert_eq!(stats.top_referrers[0].clicks, 2);
+        let recent = stats.recent.unwrap();
+        assert_eq!(recent.len(), 3);
+        assert_eq!(recent[0].client, recent[2].client);
+        assert!(!recent[0].client.contains("192.0.2.7"));
+        assert!(analytics.stats("abcde", false).recent.is_none());
+        assert_eq!(analytics.stats("fghij", true).total_clicks, 0);
+    }
+
+    #[test]
+    fn forgetting_drops_queued_hits() {
+        let analytics = Analytics::new();
+        let client: IpAddr = "192.0.2.7".parse().unwrap();
+        for _ in 0..3 {
+            analytics.record("abcde", &HeaderMap::new(), client);
+        }
+        analytics.forget("abcde");
+        analytics.record("abcde", &HeaderMap::new(), client);
+        // Hits are folded in order, so once this one counts the rest have.
+        analytics.record("fghij", &HeaderMap::new(), client);
+
+        assert_eq!(wait_for(&analytics, "fghij", 1).total_clicks, 1);
+        assert_eq!(analytics.stats("abcde", false).total_clicks, 1);
+    }
+}
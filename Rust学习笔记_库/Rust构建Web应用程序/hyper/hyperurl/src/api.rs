@@ -0,0 +1,254 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize};
+use url::form_urlencoded;
+
+use crate::error::ErrorBody;
+use crate::transfer::ImportReport;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShortenRequest {
+    pub url: String,
+    #[serde(default)]
+    pub custom_alias: Option<String>,
+    /// Lifetime in seconds; mutually exclusive with `expires_at`.
+    #[serde(default)]
+    pub ttl: Option<u64>,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl ShortenRequest {
+    pub fn for_url(url: String) -> Self {
+        ShortenRequest { url, custom_alias: None, ttl: None, expires_at: None }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ShortenResponse {
+    pub code: String,
+    pub short_url: String,
+    /// The target as stored, after normalization.
+    pub url: String,
+    pub created_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateRequest {
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub ttl: Option<u64>,
+    /// Absent keeps the current expiry, `null` makes the link permanent.
+    #[serde(default, deserialize_with = "present")]
+    pub expires_at: Option<Option<DateTime<Utc>>>,
+}
+
+fn present<'de, D, T>(d: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(d).map(Some)
+}
+
+#[derive(Debug, Serialize)]
+pub struct LinkInfo {
+    pub code: String,
+    pub short_url: String,
+    pub url: String,
+    pub created_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LinkPage {
+    pub links: Vec<LinkInfo>,
+    /// Pass back as `cursor` to fetch the next page; absent on the last one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+/// Most items accepted by one `POST /api/bulk`.
+pub const MAX_BULK: usize = 1000;
+
+/// Reads a bulk body: either a JSON array whose items are URLs or full
+/// shorten requests, or plain text with one URL per line. Items that do not
+/// parse are kept as errors so the rest of the batch still goes through.
+pub fn parse_bulk(body: &[u8]) -> Result<Vec<Result<ShortenRequest, String>>, String> {
+    let text = std::str::from_utf8(body).map_err(|e| e.to_string())?;
+    let items: Vec<_> = if text.trim_start().starts_with('[') {
+        let values: Vec<serde_json::Value> = serde_json::from_str(text).map_err(|e| e.to_string())?;
+        values
+            .into_iter()
+            .map(|value| match value {
+                serde_json::Value::String(url) => Ok(ShortenRequest::for_url(url)),
+                other => serde_json::from_value(other).map_err(|e| e.to_string()),
+            })
+            .collect()
+    } else {
+        text.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|url| Ok(ShortenRequest::for_url(url.to_string())))
+            .collect()
+    };
+    match items.len() {
+        0 => Err("no urls given".to_string()),
+        n if n > MAX_BULK => Err(format!("{} items, at most {} per request", n, MAX_BULK)),
+        _ => Ok(items),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct BulkResponse {
+    pub created: usize,
+    pub existing: usize,
+    pub failed: usize,
+    /// One entry per input item, in input order.
+    pub results: Vec<BulkResult>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BulkResult {
+    pub index: usize,
+    #[serde(flatten)]
+    pub outcome: BulkOutcome,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum BulkOutcome {
+    Shortened {
+        #[serde(flatten)]
+        link: LinkInfo,
+        created: bool,
+    },
+    Failed(ErrorBody),
+}
+
+/// Answer of `POST /api/import`. Problems are listed by line of the body;
+/// the other lines were loaded.
+#[derive(Debug, Serialize)]
+pub struct ImportResponse {
+    pub imported: usize,
+    /// Codes already holding the very same link.
+    pub unchanged: usize,
+    /// Codes holding a different link, left alone.
+    pub conflicts: Vec<ImportProblem>,
+    pub invalid: Vec<ImportProblem>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportProblem {
+    pub line: usize,
+    pub message: String,
+}
+
+impl From<ImportReport> for ImportResponse {
+    fn from(report: ImportReport) -> Self {
+        let problems = |list: Vec<(usize, String)>, describe: fn(String) -> String| {
+            list.into_iter().map(|(line, what)| ImportProblem { line, message: describe(what) }).collect()
+        };
+        ImportResponse {
+            imported: report.imported,
+            unchanged: report.unchanged,
+            conflicts: problems(report.conflicts, |code| format!("code `{}` already holds another link", code)),
+            invalid: problems(report.invalid, |message| message),
+        }
+    }
+}
+
+pub const DEFAULT_PAGE: usize = 50;
+pub const MAX_PAGE: usize = 1000;
+
+/// Query string of `GET /api/links`.
+#[derive(Debug, PartialEq)]
+pub struct ListQuery {
+    pub cursor: Option<String>,
+    pub limit: usize,
+    pub owner: Option<String>,
+    pub prefix: Option<String>,
+}
+
+impl ListQuery {
+    pub fn parse(query: &str) -> Result<Self, String> {
+        let mut out = ListQuery { cursor: None, limit: DEFAULT_PAGE, owner: None, prefix: None };
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            let value = value.into_owned();
+            match &*key {
+                "cursor" => out.cursor = Some(value),
+                "owner" => out.owner = Some(value),
+                "prefix" => out.prefix = Some(value),
+                "limit" => {
+                    out.limit = value
+                        .parse()
+                        .ok()
+                        .filter(|n| (1..=MAX_PAGE).contains(n))
+                        .ok_or_else(|| format!("limit must be between 1 and {}", MAX_PAGE))?
+                }
+                _ => return Err(format!("unknown query parameter `{}`", key)),
+            }
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_list_query() {
+        assert_eq!(
+            ListQuery::parse("").unwrap(),
+            ListQuery { cursor: None, limit: DEFAULT_PAGE, owner: None, prefix: None }
+        );
+        assert_eq!(
+            ListQuery::parse("limit=2&cursor=ab%2Fc&owner=ann&prefix=x").unwrap(),
+            ListQuery {
+                cursor: Some("ab/c".into()),
+                limit: 2,
+                owner: Some("ann".into()),
+                prefix: Some("x".into()),
+            }
+        );
+        assert!(ListQuery::parse("limit=0").is_err());
+        assert!(ListQuery::parse("limit=lots").is_err());
+        assert!(ListQuery::parse("sort=url").is_err());
+    }
+
+    #[test]
+    fn parse_bulk_bodies() {
+        let json = br#"["https://a.example/", {"url": "https://b.example/", "ttl": 60}, {"link": 1}, 7]"#;
+        let items = parse_bulk(json).unwrap();
+        assert_eq!(items.len(), 4);
+        assert_eq!(items[0].as_ref().unwrap().url, "https://a.example/");
+        assert_eq!(items[1].as_ref().unwrap().ttl, Some(60));
+        assert!(items[2].is_err());
+        assert!(items[3].is_err());
+
+        let lines = parse_bulk(b"https://a.example/\r\n\n  https://b.example/  \n").unwrap();
+        let urls: Vec<_> = lines.into_iter().map(|r| r.unwrap().url).collect();
+        assert_eq!(urls, ["https://a.example/", "https://b.example/"]);
+
+        assert!(parse_bulk(b"[1, 2").is_err());
+        assert!(parse_bulk(b"\n\n").is_err());
+        assert!(parse_bulk(b"[]").is_err());
+        assert!(parse_bulk("x\n".repeat(MAX_BULK + 1).as_bytes()).is_err());
+    }
+
+    #[test]
+    fn update_tells_null_from_absent() {
+        let keep: UpdateRequest = serde_json::from_str(r#"{"url":"https://example.com/"}"#).unwrap();
+        assert_eq!(keep.expires_at, None);
+        let clear: UpdateRequest = serde_json::from_str(r#"{"expires_at":null}"#).unwrap();
+        assert_eq!(clear.expires_at, Some(None));
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use hyper::header::AUTHORIZATION;
+use hyper::HeaderMap;
+use serde::Deserialize;
+
+use crate::error::ApiError;
+use crate::store::Link;
+
+/// One configured key. `admin` keys may change links they do not own.
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKey {
+    pub key: String,
+    pub owner: String,
+    #[serde(default)]
+    pub admin: bool,
+}
+
+impl fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ApiKey")
+            .field("key", &"<redacted>")
+            .field("owner", &self.owner)
+            .field("admin", &self.admin)
+            .finish()
+    }
+}
+
+impl ApiKey {
+    /// Reads a key file: one `owner key [admin]` entry per line, `#` starts
+    /// a comment.
+    pub fn read_file(path: &Path) -> io::Result<Vec<ApiKey>> {
+        let mut keys = Vec::new();
+        for (n, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let entry = match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [owner, key] => (owner, key, false),
+                [owner, key, "admin"] => (owner, key, true),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{}:{}: expected `owner key [admin]`", path.display(), n + 1),
+                    ))
+                }
+            };
+            let (owner, key, admin) = entry;
+            keys.push(ApiKey { owner: owner.to_string(), key: key.to_string(), admin });
+        }
+        Ok(keys)
+    }
+}
+
+/// The caller behind a valid key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Principal {
+    pub owner: String,
+    pub admin: bool,
+}
+
+impl Principal {
+    pub fn can_modify(&self, link: &Link) -> bool {
+        self.admin || link.owner.as_deref() == Some(self.owner.as_str())
+    }
+}
+
+/// Known keys, indexed by their SHA-256 so lookups never compare secrets.
+pub struct Keys {
+    by_hash: HashMap<String, Principal>,
+}
+
+impl Keys {
+    pub fn new(keys: &[ApiKey]) -> Self {
+        let by_hash = keys
+            .iter()
+            .map(|k| (digest(&k.key), Principal { owner: k.owner.clone(), admin: k.admin }))
+            .collect();
+        Keys { by_hash }
+    }
+
+    /// Without configured keys the service stays open and callers are
+    /// anonymous.
+    pub fn is_enabled(&self) -> bool {
+        !self.by_hash.is_empty()
+    }
+
+    /// Identifies the caller. `Ok(None)` is an anonymous caller, which is
+    /// only accepted while no keys are configured.
+    pub fn authenticate(&self, headers: &HeaderMap) -> Result<Option<Principal>, ApiError> {
+        match headers.get(AUTHORIZATION) {
+            None if !self.is_enabled() => Ok(None),
+            None => Err(ApiError::Unauthorized("missing api key")),
+            Some(value) => {
+                let key = value
+                    .to_str()
+                    .ok()
+                    .and_then(|v| v.strip_prefix("Bearer "))
+                    .map(str::trim)
+                    .ok_or(ApiError::Unauthorized("expected `Authorization: Bearer <key>`"))?;
+                self.by_hash
+                    .get(&digest(key))
+                    .cloned()
+                    .map(Some)
+                    .ok_or(ApiError::Unauthorized("unknown api key"))
+            }
+        }
+    }
+
+    /// Like `authenticate`, but an anonymous caller is always refused.
+    pub fn require(&self, headers: &HeaderMap) -> Result<Principal, ApiError> {
+        self.authenticate(headers)?
+            .ok_or(ApiError::Unauthorized("api keys are not configured"))
+    }
+}
+
+fn digest(key: &str) -> String {
+    let mut sha = Sha256::new();
+    sha.input_str(key);
+    sha.result_str()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> Keys {
+        Keys::new(&[
+            ApiKey { key: "alice-secret-key-0001".into(), owner: "alice".into(), admin: false },
+            ApiKey { key: "root-secret-key-00001".into(), owner: "ops".into(), admin: true },
+        ])
+    }
+
+    fn bearer(key: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, format!("Bearer {}", key).parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn authenticates_bearer_keys() {
+        let keys = keys();
+        let alice = keys.authenticate(&bearer("alice-secret-key-0001")).unwrap().unwrap();
+        assert_eq!(alice, Principal { owner: "alice".into(), admin: false });
+        assert!(keys.authenticate(&bearer("wrong")).is_err());
+        assert!(keys.authenticate(&HeaderMap::new()).is_err());
+
+        let open = Keys::new(&[]);
+        assert_eq!(open.authenticate(&HeaderMap::new()).unwrap(), None);
+        assert!(open.require(&HeaderMap::new()).is_err());
+    }
+
+    #[test]
+    fn only_owner_or_admin_modifies() {
+        let keys = keys();
+        let alice = keys.require(&bearer("alice-secret-key-0001")).unwrap();
+        let admin = keys.require(&bearer("root-secret-key-00001")).unwrap();
+        let link = Link::new("https://example.com/".into());
+
+        assert!(alice.can_modify(&link.clone().owned_by(Some("alice".into()))));
+        assert!(!alice.can_modify(&link.clone().owned_by(Some("bob".into()))));
+        assert!(!alice.can_modify(&link));
+        assert!(admin.can_modify(&link.owned_by(Some("bob".into()))));
+    }
+
+    #[test]
+    fn reads_key_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys");
+        fs::write(&path, "# team keys\nalice alice-secret-key-0001\n\nops root-secret-key-00001 admin # on call\n").unwrap();
+        let keys = ApiKey::read_file(&path).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[1], ApiKey { key: "root-secret-key-00001".into(), owner: "ops".into(), admin: true });
+
+        fs::write(&path, "alice\n").unwrap();
+        assert!(ApiKey::read_file(&path).is_err());
+        fs::write(&path, "alice key superuser\n").unwrap();
+        assert!(ApiKey::read_file(&path).is_err());
+    }
+}
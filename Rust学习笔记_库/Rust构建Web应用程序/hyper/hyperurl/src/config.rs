@@ -0,0 +1,612 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use log::LevelFilter;
+use serde::Deserialize;
+use url::Url;
+
+use crate::auth::ApiKey;
+use crate::ratelimit::{RateLimit, RateLimiter};
+use crate::shortener::CodeScheme;
+use crate::store::StoreConfig;
+use crate::validate::{AliasPolicy, UrlPolicy};
+
+const MIN_KEY_LENGTH: usize = 16;
+
+/// Effective server settings. Each value comes from the first source that
+/// sets it: command line, environment, config file, built-in default.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub listen: SocketAddr,
+    pub base_url: String,
+    pub codes: CodeScheme,
+    pub code_length: usize,
+    /// Where links are kept. Click statistics are not stored with them and
+    /// start over on every restart.
+    pub store: StoreConfig,
+    pub log_level: String,
+    pub max_url_length: usize,
+    pub strip_fragments: bool,
+    /// Seconds between sweeps for expired links.
+    pub sweep_interval: u64,
+    pub alias_min_length: usize,
+    pub alias_max_length: usize,
+    /// Words withheld from aliases on top of the built-in route names.
+    pub reserved_aliases: Vec<String>,
+    /// Keys from `[[api_keys]]` plus those in `api_keys_file`. Without any,
+    /// link creation is open to anonymous callers.
+    pub api_keys: Vec<ApiKey>,
+    /// Link creations per client, counted per API key owner or address.
+    pub create_rate_limit: RateLimit,
+    /// Items of `POST /api/bulk` per client, a budget of its own so a
+    /// migration is not held to the pace of interactive creation.
+    pub bulk_rate_limit: RateLimit,
+    /// Redirects per client address.
+    pub redirect_rate_limit: RateLimit,
+    /// Largest request body accepted, in bytes.
+    pub max_body_size: usize,
+    /// Seconds a client gets to send the request headers.
+    pub header_timeout: u64,
+    /// Seconds a client gets to send the request body.
+    pub body_timeout: u64,
+    /// PEM certificate chain and private key; with both set the server
+    /// speaks HTTPS only. Reread on SIGHUP.
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: ([127, 0, 0, 1], 3002).into(),
+            base_url: "https://u.rl/".to_string(),
+            codes: CodeScheme::Hash,
+            code_length: 5,
+            store: StoreConfig::Memory,
+            log_level: "hyperurl=info".to_string(),
+            max_url_length: UrlPolicy::default().max_length,
+            strip_fragments: false,
+            sweep_interval: 60,
+            alias_min_length: AliasPolicy::default().min_length,
+            alias_max_length: AliasPolicy::default().max_length,
+            reserved_aliases: Vec::new(),
+            api_keys: Vec::new(),
+            create_rate_limit: RateLimit::Every { count: 60, per: Duration::from_secs(60) },
+            bulk_rate_limit: RateLimit::Every { count: 10_000, per: Duration::from_secs(3600) },
+            redirect_rate_limit: RateLimit::Off,
+            max_body_size: 1 << 20,
+            header_timeout: 10,
+            body_timeout: 30,
+            tls_cert: None,
+            tls_key: None,
+        }
+    }
+}
+
+/// Command line flags. `Config::load` also reads each one from a
+/// `HYPERURL_*` variable, e.g. `--max-url-length` from
+/// `HYPERURL_MAX_URL_LENGTH`.
+#[derive(Debug, Default, clap::Args)]
+pub struct ConfigArgs {
+    /// TOML config file
+    #[arg(long, short)]
+    pub config: Option<PathBuf>,
+    /// Address to listen on
+    #[arg(long)]
+    pub listen: Option<SocketAddr>,
+    /// Public prefix of short links, e.g. https://u.rl/
+    #[arg(long)]
+    pub base_url: Option<String>,
+    /// Code generator: hash, counter or random
+    #[arg(long)]
+    pub codes: Option<CodeScheme>,
+    /// Length of generated codes; counter codes are padded with leading zeros
+    #[arg(long)]
+    pub code_length: Option<usize>,
+    /// Storage backend: memory or file:<path>; click stats stay in memory
+    #[arg(long)]
+    pub store: Option<StoreConfig>,
+    /// Log filter, e.g. info or hyperurl=debug
+    #[arg(long)]
+    pub log_level: Option<String>,
+    /// Longest URL accepted for shortening
+    #[arg(long)]
+    pub max_url_length: Option<usize>,
+    /// Drop #fragments from URLs before shortening
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    pub strip_fragments: Option<bool>,
+    /// Seconds between sweeps for expired links
+    #[arg(long)]
+    pub sweep_interval: Option<u64>,
+    /// Shortest custom alias accepted
+    #[arg(long)]
+    pub alias_min_length: Option<usize>,
+    /// Longest custom alias accepted
+    #[arg(long)]
+    pub alias_max_length: Option<usize>,
+    /// Extra words no alias may use, comma separated
+    #[arg(long, value_delimiter = ',')]
+    pub reserved_aliases: Option<Vec<String>>,
+    /// File of API keys, one `owner key [admin]` per line
+    #[arg(long)]
+    pub api_keys_file: Option<PathBuf>,
+    /// Link creations allowed per client, e.g. 60/m, or off
+    #[arg(long)]
+    pub create_rate_limit: Option<RateLimit>,
+    /// Bulk items allowed per client, e.g. 10000/h, or off
+    #[arg(long)]
+    pub bulk_rate_limit: Option<RateLimit>,
+    /// Redirects allowed per client address, e.g. 600/m, or off
+    #[arg(long)]
+    pub redirect_rate_limit: Option<RateLimit>,
+    /// Largest request body accepted, in bytes
+    #[arg(long)]
+    pub max_body_size: Option<usize>,
+    /// Seconds a client gets to send the request headers
+    #[arg(long)]
+    pub header_timeout: Option<u64>,
+    /// Seconds a client gets to send the request body
+    #[arg(long)]
+    pub body_timeout: Option<u64>,
+    /// PEM certificate chain to serve HTTPS with
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key for --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    listen: Option<SocketAddr>,
+    base_url: Option<String>,
+    codes: Option<CodeScheme>,
+    code_length: Option<usize>,
+    store: Option<StoreConfig>,
+    log_level: Option<String>,
+    max_url_length: Option<usize>,
+    strip_fragments: Option<bool>,
+    sweep_interval: Option<u64>,
+    alias_min_length: Option<usize>,
+    alias_max_length: Option<usize>,
+    reserved_aliases: Option<Vec<String>>,
+    api_keys: Vec<ApiKey>,
+    api_keys_file: Option<PathBuf>,
+    create_rate_limit: Option<RateLimit>,
+    bulk_rate_limit: Option<RateLimit>,
+    redirect_rate_limit: Option<RateLimit>,
+    max_body_size: Option<usize>,
+    header_timeout: Option<u64>,
+    body_timeout: Option<u64>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "cannot read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "cannot parse {}: {}", path.display(), e),
+            ConfigError::Invalid(reason) => write!(f, "invalid config: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Layers `args` over the `HYPERURL_*` variables among `vars`, those
+    /// over the config file either names, and validates the result.
+    pub fn load<I>(args: &ConfigArgs, vars: I) -> Result<Config, ConfigError>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let env = Environment::new(vars);
+        let file = match args.config.clone().or(env.get("config")?) {
+            Some(ref path) => env.layer()?.over(read_file(path)?),
+            None => env.layer()?,
+        };
+        let defaults = Config::default();
+
+        let mut api_keys = file.api_keys;
+        if let Some(path) = args.api_keys_file.as_ref().or(file.api_keys_file.as_ref()) {
+            api_keys.extend(ApiKey::read_file(path).map_err(|e| ConfigError::Read(path.clone(), e))?);
+        }
+
+        Config {
+            listen: args.listen.or(file.listen).unwrap_or(defaults.listen),
+            base_url: args.base_url.clone().or(file.base_url).unwrap_or(defaults.base_url),
+            codes: args.codes.or(file.codes).unwrap_or(defaults.codes),
+            code_length: args.code_length.or(file.code_length).unwrap_or(defaults.code_length),
+            store: args.store.clone().or(file.store).unwrap_or(defaults.store),
+            log_level: args.log_level.clone().or(file.log_level).unwrap_or(defaults.log_level),
+            max_url_length: args.max_url_length.or(file.max_url_length).unwrap_or(defaults.max_url_length),
+            strip_fragments: args.strip_fragments.or(file.strip_fragments).unwrap_or(defaults.strip_fragments),
+            sweep_interval: args.sweep_interval.or(file.sweep_interval).unwrap_or(defaults.sweep_interval),
+            alias_min_length: args.alias_min_length.or(file.alias_min_length).unwrap_or(defaults.alias_min_length),
+            alias_max_length: args.alias_max_length.or(file.alias_max_length).unwrap_or(defaults.alias_max_length),
+            reserved_aliases: args.reserved_aliases.clone().or(file.reserved_aliases).unwrap_or(defaults.reserved_aliases),
+            api_keys,
+            create_rate_limit: args.create_rate_limit.or(file.create_rate_limit).unwrap_or(defaults.create_rate_limit),
+            bulk_rate_limit: args.bulk_rate_limit.or(file.bulk_rate_limit).unwrap_or(defaults.bulk_rate_limit),
+            redirect_rate_limit: args.redirect_rate_limit.or(file.redirect_rate_limit).unwrap_or(defaults.redirect_rate_limit),
+            max_body_size: args.max_body_size.or(file.max_body_size).unwrap_or(defaults.max_body_size),
+            header_timeout: args.header_timeout.or(file.header_timeout).unwrap_or(defaults.header_timeout),
+            body_timeout: args.body_timeout.or(file.body_timeout).unwrap_or(defaults.body_timeout),
+            tls_cert: args.tls_cert.clone().or(file.tls_cert),
+            tls_key: args.tls_key.clone().or(file.tls_key),
+        }
+        .validated()
+    }
+
+    pub fn url_policy(&self) -> UrlPolicy {
+        UrlPolicy { max_length: self.max_url_length, strip_fragment: self.strip_fragments }
+    }
+
+    pub fn alias_policy(&self) -> AliasPolicy {
+        let mut policy = AliasPolicy {
+            min_length: self.alias_min_length,
+            max_length: self.alias_max_length,
+            ..AliasPolicy::default()
+        };
+        policy.reserved.extend(self.reserved_aliases.iter().map(|w| w.to_ascii_lowercase()));
+        policy
+    }
+
+    pub fn rate_limiter(&self) -> RateLimiter {
+        RateLimiter::new(self.create_rate_limit, self.bulk_rate_limit, self.redirect_rate_limit)
+    }
+
+    pub fn sweep_every(&self) -> Duration {
+        Duration::from_secs(self.sweep_interval)
+    }
+
+    pub fn header_read_timeout(&self) -> Duration {
+        Duration::from_secs(self.header_timeout)
+    }
+
+    pub fn body_read_timeout(&self) -> Duration {
+        Duration::from_secs(self.body_timeout)
+    }
+
+    /// Certificate and key paths, when HTTPS is configured.
+    pub fn tls_files(&self) -> Option<(&Path, &Path)> {
+        Some((self.tls_cert.as_deref()?, self.tls_key.as_deref()?))
+    }
+
+    pub fn short_url(&self, code: &str) -> String {
+        format!("{}{}", self.base_url, code)
+    }
+
+    fn validated(mut self) -> Result<Config, ConfigError> {
+        let base = Url::parse(&self.base_url)
+            .map_err(|e| invalid(format!("base_url `{}`: {}", self.base_url, e)))?;
+        if !matches!(base.scheme(), "http" | "https") || base.host_str().is_none() {
+            return Err(invalid(format!("base_url `{}` must be an http(s) URL with a host", self.base_url)));
+        }
+        if base.query().is_some() || base.fragment().is_some() {
+            return Err(invalid(format!("base_url `{}` must not have a query or fragment", self.base_url)));
+        }
+        self.base_url = String::from(base);
+        if !self.base_url.ends_with('/') {
+            self.base_url.push('/');
+        }
+
+        if !(3..=64).contains(&self.code_length) {
+            return Err(invalid(format!("code_length {} is outside 3..=64", self.code_length)));
+        }
+        if self.max_url_length == 0 {
+            return Err(invalid("max_url_length must be positive".to_string()));
+        }
+        if self.alias_min_length == 0 || self.alias_min_length > self.alias_max_length {
+            return Err(invalid(format!(
+                "alias lengths {}..={} are not a valid range",
+                self.alias_min_length, self.alias_max_length
+            )));
+        }
+        let mut seen = std::collections::HashSet::new();
+        for key in &self.api_keys {
+            if key.owner.is_empty() {
+                return Err(invalid("api key owners must not be empty".to_string()));
+            }
+            if key.key.len() < MIN_KEY_LENGTH {
+                return Err(invalid(format!(
+                    "api key of `{}` is shorter than {} characters",
+                    key.owner, MIN_KEY_LENGTH
+                )));
+            }
+            if !seen.insert(&key.key) {
+                return Err(invalid(format!("api key of `{}` is listed twice", key.owner)));
+            }
+        }
+        if self.sweep_interval == 0 {
+            return Err(invalid("sweep_interval must be positive".to_string()));
+        }
+        if self.max_body_size == 0 {
+            return Err(invalid("max_body_size must be positive".to_string()));
+        }
+        if self.header_timeout == 0 || self.body_timeout == 0 {
+            return Err(invalid("header_timeout and body_timeout must be positive".to_string()));
+        }
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(_), Some(_)) if !cfg!(feature = "tls") => {
+                return Err(invalid("tls_cert is set but TLS support was not built in".to_string()));
+            }
+            (Some(_), None) | (None, Some(_)) => {
+                return Err(invalid("tls_cert and tls_key must be set together".to_string()));
+            }
+            _ => {}
+        }
+        check_log_filter(&self.log_level)?;
+        Ok(self)
+    }
+}
+
+impl FileConfig {
+    /// `self` with unset values taken from `below`.
+    fn over(self, below: FileConfig) -> FileConfig {
+        let mut api_keys = self.api_keys;
+        api_keys.extend(below.api_keys);
+        FileConfig {
+            listen: self.listen.or(below.listen),
+            base_url: self.base_url.or(below.base_url),
+            codes: self.codes.or(below.codes),
+            code_length: self.code_length.or(below.code_length),
+            store: self.store.or(below.store),
+            log_level: self.log_level.or(below.log_level),
+            max_url_length: self.max_url_length.or(below.max_url_length),
+            strip_fragments: self.strip_fragments.or(below.strip_fragments),
+            sweep_interval: self.sweep_interval.or(below.sweep_interval),
+            alias_min_length: self.alias_min_length.or(below.alias_min_length),
+            alias_max_length: self.alias_max_length.or(below.alias_max_length),
+            reserved_aliases: self.reserved_aliases.or(below.reserved_aliases),
+            api_keys,
+            api_keys_file: self.api_keys_file.or(below.api_keys_file),
+            create_rate_limit: self.create_rate_limit.or(below.create_rate_limit),
+            bulk_rate_limit: self.bulk_rate_limit.or(below.bulk_rate_limit),
+            redirect_rate_limit: self.redirect_rate_limit.or(below.redirect_rate_limit),
+            max_body_size: self.max_body_size.or(below.max_body_size),
+            header_timeout: self.header_timeout.or(below.header_timeout),
+            body_timeout: self.body_timeout.or(below.body_timeout),
+            tls_cert: self.tls_cert.or(below.tls_cert),
+            tls_key: self.tls_key.or(below.tls_key),
+        }
+    }
+}
+
+/// The non-empty `HYPERURL_*` variables of a process environment.
+struct Environment(HashMap<String, String>);
+
+impl Environment {
+    fn new<I: IntoIterator<Item = (String, String)>>(vars: I) -> Environment {
+        Environment(
+            vars.into_iter()
+                .filter(|(name, value)| name.starts_with("HYPERURL_") && !value.is_empty())
+                .collect(),
+        )
+    }
+
+    /// The variable for config `key`, parsed as a flag value would be.
+    fn get<T>(&self, key: &str) -> Result<Option<T>, ConfigError>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        let name = format!("HYPERURL_{}", key.to_ascii_uppercase());
+        self.0
+            .get(&name)
+            .map(|value| value.parse().map_err(|e| invalid(format!("{} `{}`: {}", name, value, e))))
+            .transpose()
+    }
+
+    fn layer(&self) -> Result<FileConfig, ConfigError> {
+        Ok(FileConfig {
+            listen: self.get("listen")?,
+            base_url: self.get("base_url")?,
+            codes: self.get("codes")?,
+            code_length: self.get("code_length")?,
+            store: self.get("store")?,
+            log_level: self.get("log_level")?,
+            max_url_length: self.get("max_url_length")?,
+            strip_fragments: self.get("strip_fragments")?,
+            sweep_interval: self.get("sweep_interval")?,
+            alias_min_length: self.get("alias_min_length")?,
+            alias_max_length: self.get("alias_max_length")?,
+            reserved_aliases: self
+                .get::<String>("reserved_aliases")?
+                .map(|words| words.split(',').map(str::to_string).collect()),
+            api_keys: Vec::new(),
+            api_keys_file: self.get("api_keys_file")?,
+            create_rate_limit: self.get("create_rate_limit")?,
+            bulk_rate_limit: self.get("bulk_rate_limit")?,
+            redirect_rate_limit: self.get("redirect_rate_limit")?,
+            max_body_size: self.get("max_body_size")?,
+            header_timeout: self.get("header_timeout")?,
+            body_timeout: self.get("body_timeout")?,
+            tls_cert: self.get("tls_cert")?,
+            tls_key: self.get("tls_key")?,
+        })
+    }
+}
+
+fn read_file(path: &Path) -> Result<FileConfig, ConfigError> {
+    let text = fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+    toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+}
+
+/// Accepts env_logger style filters: `level`, `module` or `module=level`,
+/// comma separated.
+fn check_log_filter(filter: &str) -> Result<(), ConfigError> {
+    for directive in filter.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        if let Some((_, level)) = directive.split_once('=') {
+            level
+                .parse::<LevelFilter>()
+                .map_err(|_| invalid(format!("unknown log level `{}` in `{}`", level, filter)))?;
+        }
+    }
+    Ok(())
+}
+
+fn invalid(reason: String) -> ConfigError {
+    ConfigError::Invalid(reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::io::Write;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        config: ConfigArgs,
+    }
+
+    fn args(argv: &[&str]) -> ConfigArgs {
+        Cli::try_parse_from(std::iter::once("hyperurl").chain(argv.iter().cloned())).unwrap().config
+    }
+
+    fn load(argv: &[&str]) -> Result<Config, ConfigError> {
+        Config::load(&args(argv), None)
+    }
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        let config = Config::load(&ConfigArgs::default(), None).unwrap();
+        assert_eq!(config.listen, "127.0.0.1:3002".parse().unwrap());
+        assert_eq!(config.short_url("abcde"), "https://u.rl/abcde");
+    }
+
+    #[test]
+    fn flags_override_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            file,
+            "listen = \"0.0.0.0:8080\"\nbase_url = \"https://s.example\"\ncode_length = 7\nstore = \"file:/tmp/links.db\"\ncreate_rate_limit = \"5/s\""
+        )
+        .unwrap();
+        let path = file.path().to_str().unwrap();
+
+        let config = Config::load(&args(&[
+            "-c",
+            path,
+            "--code-length",
+            "9",
+            "--strip-fragments",
+            "--redirect-rate-limit",
+            "100/m",
+        ]), None)
+        .unwrap();
+        assert_eq!(config.listen, "0.0.0.0:8080".parse().unwrap());
+        assert_eq!(config.base_url, "https://s.example/");
+        assert_eq!(config.code_length, 9);
+        assert_eq!(config.store, StoreConfig::File("/tmp/links.db".into()));
+        assert!(config.strip_fragments);
+        assert_eq!(config.create_rate_limit.to_string(), "5/s");
+        assert_eq!(config.redirect_rate_limit.to_string(), "100/m");
+    }
+
+    #[test]
+    fn extra_reserved_aliases() {
+        let config = load(&["--reserved-aliases", "Docs,blog"]).unwrap();
+        let policy = config.alias_policy();
+        assert!(policy.is_reserved("docs"));
+        assert!(policy.is_reserved("BLOG"));
+        assert!(policy.is_reserved("stats"));
+    }
+
+    #[test]
+    fn environment_sits_between_flags_and_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "max_url_length = 100").unwrap();
+        let path = file.path().to_str().unwrap();
+
+        let vars = env(&[("HYPERURL_MAX_URL_LENGTH", "200"), ("HYPERURL_RESERVED_ALIASES", "docs,blog")]);
+        let from_env = Config::load(&args(&["-c", path]), vars.clone()).unwrap();
+        let from_flag = Config::load(&args(&["-c", path, "--max-url-length", "300"]), vars).unwrap();
+
+        assert_eq!(from_env.max_url_length, 200);
+        assert_eq!(from_env.reserved_aliases, ["docs", "blog"]);
+        assert_eq!(from_flag.max_url_length, 300);
+        assert_eq!(load(&["-c", path]).unwrap().max_url_length, 100);
+
+        let named = Config::load(&ConfigArgs::default(), env(&[("HYPERURL_CONFIG", path)])).unwrap();
+        assert_eq!(named.max_url_length, 100);
+        assert!(matches!(
+            Config::load(&ConfigArgs::default(), env(&[("HYPERURL_CODE_LENGTH", "seven")])),
+            Err(ConfigError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn api_keys_from_file_and_key_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let keys = dir.path().join("keys");
+        fs::write(&keys, "bob bob-secret-key-000001\n").unwrap();
+        let config = dir.path().join("hyperurl.toml");
+        fs::write(
+            &config,
+            format!(
+                "api_keys_file = {:?}\n[[api_keys]]\nkey = \"ops-secret-key-000001\"\nowner = \"ops\"\nadmin = true\n",
+                keys
+            ),
+        )
+        .unwrap();
+
+        let loaded = load(&["-c", config.to_str().unwrap()]).unwrap();
+        let owners: Vec<_> = loaded.api_keys.iter().map(|k| (k.owner.as_str(), k.admin)).collect();
+        assert_eq!(owners, [("ops", true), ("bob", false)]);
+        assert!(!format!("{:?}", loaded).contains("secret"));
+
+        fs::write(&keys, "bob short\n").unwrap();
+        assert!(matches!(
+            load(&["-c", config.to_str().unwrap()]),
+            Err(ConfigError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_values() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "lisen = \"0.0.0.0:8080\"").unwrap();
+        let path = file.path().to_str().unwrap();
+        assert!(matches!(load(&["-c", path]), Err(ConfigError::Parse(..))));
+
+        for bad in &[
+            &["--base-url", "ftp://u.rl/"][..],
+            &["--base-url", "https://u.rl/?x=1"],
+            &["--code-length", "2"],
+            &["--log-level", "hyperurl=loud"],
+            &["--alias-min-length", "8", "--alias-max-length", "4"],
+            &["--max-body-size", "0"],
+            &["--body-timeout", "0"],
+        ] {
+            assert!(matches!(load(bad), Err(ConfigError::Invalid(_))), "{:?}", bad);
+        }
+        assert!(Cli::try_parse_from(["hyperurl", "--store", "redis"]).is_err());
+        assert!(Cli::try_parse_from(["hyperurl", "--create-rate-limit", "lots"]).is_err());
+        assert!(Cli::try_parse_from(["hyperurl", "--tls-cert", "cert.pem"]).is_err());
+
+        fs::write(path, "tls_key = \"key.pem\"\n").unwrap();
+        assert!(matches!(load(&["-c", path]), Err(ConfigError::Invalid(_))));
+    }
+}
@@ -0,0 +1,187 @@
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use hyper::header::{ALLOW, CONNECTION, CONTENT_TYPE, RETRY_AFTER, WWW_AUTHENTICATE};
+use hyper::{Response, StatusCode};
+use log::error;
+use serde::Serialize;
+
+use crate::service::Body;
+use crate::shortener::{ClaimError, InvalidExpiry};
+use crate::validate::{InvalidAlias, InvalidUrl};
+
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    InvalidUrl(InvalidUrl),
+    InvalidAlias(InvalidAlias),
+    Unauthorized(&'static str),
+    /// Carries what the caller is not allowed to do.
+    Forbidden(&'static str),
+    NotFound,
+    Expired,
+    MethodNotAllowed(&'static str),
+    /// The body limit that was exceeded, in bytes.
+    PayloadTooLarge(usize),
+    RequestTimeout,
+    AliasTaken(String),
+    /// Carries how long until the client may try again.
+    RateLimited(Duration),
+    /// The storage backend has no such operation.
+    Unsupported(&'static str),
+    /// Carries why the service cannot take traffic.
+    NotReady(String),
+    Store(io::Error),
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorBody {
+    pub error: &'static str,
+    pub message: String,
+    /// Seconds to wait before retrying a rate limited request; the
+    /// `Retry-After` header says the same, but bulk items have none.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) | ApiError::InvalidUrl(_) | ApiError::InvalidAlias(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound => StatusCode::NOT_FOUND,
+            ApiError::Expired => StatusCode::GONE,
+            ApiError::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::RequestTimeout => StatusCode::REQUEST_TIMEOUT,
+            ApiError::AliasTaken(_) => StatusCode::CONFLICT,
+            ApiError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Unsupported(_) => StatusCode::NOT_IMPLEMENTED,
+            ApiError::NotReady(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Store(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Stable identifier clients can match on.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::InvalidUrl(_) => "invalid_url",
+            ApiError::InvalidAlias(_) => "invalid_alias",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::NotFound => "not_found",
+            ApiError::Expired => "expired",
+            ApiError::MethodNotAllowed(_) => "method_not_allowed",
+            ApiError::PayloadTooLarge(_) => "payload_too_large",
+            ApiError::RequestTimeout => "request_timeout",
+            ApiError::AliasTaken(_) => "alias_taken",
+            ApiError::RateLimited(_) => "rate_limited",
+            ApiError::Unsupported(_) => "unsupported",
+            ApiError::NotReady(_) => "not_ready",
+            ApiError::Store(_) => "internal",
+        }
+    }
+
+    /// The JSON error document; store errors are logged on the way.
+    pub fn body(&self) -> ErrorBody {
+        if let ApiError::Store(ref e) = self {
+            error!("store error: {}", e);
+        }
+        let retry_after = match *self {
+            ApiError::RateLimited(wait) => Some(retry_after(wait)),
+            _ => None,
+        };
+        ErrorBody { error: self.kind(), message: self.to_string(), retry_after }
+    }
+
+    pub fn into_response(self) -> Response<Body> {
+        let body = self.body();
+        let mut res = Response::builder()
+            .status(self.status())
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_vec(&body).unwrap()))
+            .unwrap();
+        match self {
+            ApiError::MethodNotAllowed(allow) => {
+                res.headers_mut().insert(ALLOW, allow.parse().unwrap());
+            }
+            ApiError::Unauthorized(_) => {
+                res.headers_mut().insert(WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+            }
+            ApiError::RateLimited(wait) => {
+                res.headers_mut().insert(RETRY_AFTER, retry_after(wait).into());
+            }
+            // The rest of the body is not worth reading; drop the connection.
+            ApiError::PayloadTooLarge(_) | ApiError::RequestTimeout => {
+                res.headers_mut().insert(CONNECTION, "close".parse().unwrap());
+            }
+            _ => {}
+        }
+        res
+    }
+}
+
+/// Whole seconds, rounded up so clients never retry too early.
+fn retry_after(wait: Duration) -> u64 {
+    wait.as_secs() + u64::from(wait.subsec_nanos() > 0)
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApiError::BadRequest(reason) => write!(f, "bad request: {}", reason),
+            ApiError::InvalidUrl(reason) => write!(f, "invalid url: {}", reason),
+            ApiError::InvalidAlias(reason) => write!(f, "invalid alias: {}", reason),
+            ApiError::Unauthorized(reason) => write!(f, "unauthorized: {}", reason),
+            ApiError::Forbidden(reason) => write!(f, "{}", reason),
+            ApiError::NotFound => write!(f, "no such short link"),
+            ApiError::Expired => write!(f, "short link has expired"),
+            ApiError::MethodNotAllowed(allow) => write!(f, "method not allowed, use {}", allow),
+            ApiError::PayloadTooLarge(limit) => write!(f, "request body is larger than {} bytes", limit),
+            ApiError::RequestTimeout => write!(f, "request body was not received in time"),
+            ApiError::AliasTaken(alias) => write!(f, "alias `{}` is already taken", alias),
+            ApiError::RateLimited(wait) => write!(f, "too many requests, retry in {}s", retry_after(*wait)),
+            ApiError::Unsupported(what) => write!(f, "the store does not support {}", what),
+            ApiError::NotReady(reason) => write!(f, "not ready: {}", reason),
+            // Storage details stay in the server log.
+            ApiError::Store(_) => write!(f, "internal storage error"),
+        }
+    }
+}
+
+impl From<io::Error> for ApiError {
+    fn from(e: io::Error) -> Self {
+        ApiError::Store(e)
+    }
+}
+
+impl From<InvalidUrl> for ApiError {
+    fn from(e: InvalidUrl) -> Self {
+        ApiError::InvalidUrl(e)
+    }
+}
+
+impl From<InvalidAlias> for ApiError {
+    fn from(e: InvalidAlias) -> Self {
+        ApiError::InvalidAlias(e)
+    }
+}
+
+impl From<ClaimError> for ApiError {
+    fn from(e: ClaimError) -> Self {
+        match e {
+            ClaimError::Invalid(e) => ApiError::InvalidAlias(e),
+            ClaimError::Taken(alias) => ApiError::AliasTaken(alias),
+            ClaimError::Store(e) => ApiError::Store(e),
+        }
+    }
+}
+
+impl From<InvalidExpiry> for ApiError {
+    fn from(e: InvalidExpiry) -> Self {
+        ApiError::BadRequest(e.to_string())
+    }
+}
@@ -0,0 +1,76 @@
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use log::{error, info};
+
+use crate::analytics::Analytics;
+use crate::store::UrlStore;
+
+/// Removes expired links from `db` every `every`, forever, along with their
+/// click counters so a code handed out again starts from zero.
+pub async fn sweep(db: Arc<dyn UrlStore>, analytics: Arc<Analytics>, every: Duration) {
+    let mut ticks = tokio::time::interval(every);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        let (db, analytics) = (db.clone(), analytics.clone());
+        // File-backed stores fsync and forgetting may wait on the analytics
+        // queue, so keep both off the async workers.
+        let swept = tokio::task::spawn_blocking(move || {
+            let codes = db.remove_expired(Utc::now())?;
+            for code in &codes {
+                analytics.forget(code);
+            }
+            Ok::<_, io::Error>(codes)
+        });
+        match swept.await {
+            Ok(Ok(codes)) => {
+                if !codes.is_empty() {
+                    info!("swept {} expired links", codes.len());
+                }
+            }
+            Ok(Err(e)) => error!("expiry sweep failed: {}", e),
+            Err(e) => error!("expiry sweep panicked: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{Link, MemoryStore};
+    use hyper::HeaderMap;
+
+    async fn clicks(analytics: &Analytics, code: &str) -> u64 {
+        // Hits are folded in by a background thread.
+        for _ in 0..100 {
+            if analytics.stats(code, false).total_clicks > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        analytics.stats(code, false).total_clicks
+    }
+
+    #[tokio::test]
+    async fn swept_codes_lose_their_clicks() {
+        let db: Arc<dyn UrlStore> = Arc::new(MemoryStore::new());
+        let analytics = Arc::new(Analytics::new());
+        let soon = Utc::now() + chrono::Duration::milliseconds(50);
+        db.insert("flash".into(), Link::new("https://example.com/".into()).expiring(Some(soon))).unwrap();
+        analytics.record("flash", &HeaderMap::new(), [127, 0, 0, 1].into());
+        assert_eq!(clicks(&analytics, "flash").await, 1);
+
+        tokio::spawn(sweep(db.clone(), analytics.clone(), Duration::from_millis(20)));
+        for _ in 0..100 {
+            if db.is_empty() && analytics.stats("flash", false).total_clicks == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert!(db.is_empty());
+        assert_eq!(analytics.stats("flash", false).total_clicks, 0);
+    }
+}
@@ -0,0 +1,16 @@
+pub mod analytics;
+pub mod api;
+pub mod auth;
+pub mod config;
+pub mod error;
+pub mod expiry;
+pub mod metrics;
+#[cfg(feature = "qr")]
+pub mod qr;
+pub mod ratelimit;
+pub mod server;
+pub mod service;
+#[cfg(feature = "tls")]
+pub mod tls;
+
+pub use hyperurl_core::{shortener, store, transfer, validate};
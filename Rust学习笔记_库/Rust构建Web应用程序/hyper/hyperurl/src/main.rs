@@ -1,23 +1,176 @@
 use log::{info, error};
-use std::env;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
 
-use hyper::Server;
-use hyper::service::service_fn;
+use clap::{Parser, Subcommand};
+use tokio::net::TcpListener;
 
-use hyper::rt::{self, Future};
+use hyperurl::config::{Config, ConfigArgs};
+use hyperurl::expiry;
+use hyperurl::server::{serve, shutdown_signal};
+use hyperurl::service::App;
+use hyperurl::store::{StoreConfig, UrlStore};
+#[cfg(feature = "tls")]
+use hyperurl::{server::serve_tls, tls::{self, Certificates}};
+use hyperurl::transfer::{self, Format};
 
-mod shortener;
-mod service;
-use crate::service::url_service;
+/// URL shortener service
+#[derive(Parser)]
+#[command(
+    version,
+    after_help = "Each option may also be set through a HYPERURL_* variable named after it, \
+                  e.g. HYPERURL_MAX_URL_LENGTH; flags win over variables, variables over the config file."
+)]
+struct Cli {
+    #[command(flatten)]
+    config: ConfigArgs,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the server (the default)
+    Serve,
+    /// Dump every link, with its code and metadata
+    Export {
+        /// jsonl or csv; guessed from the output file name otherwise
+        #[arg(long)]
+        format: Option<Format>,
+        /// Write here instead of stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Load links written by `export`; stop the server first
+    Import {
+        /// jsonl or csv; guessed from the input file name otherwise
+        #[arg(long)]
+        format: Option<Format>,
+        /// Replace links whose code is taken instead of skipping them
+        #[arg(long)]
+        overwrite: bool,
+        /// Read this file instead of stdin
+        input: Option<PathBuf>,
+    },
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    let vars = std::env::vars_os().filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)));
+    let config = Config::load(&cli.config, vars).unwrap_or_else(|e| {
+        eprintln!("hyperurl: {}", e);
+        process::exit(2);
+    });
+    pretty_env_logger::formatted_builder()
+        .parse_filters(&config.log_level)
+        .init();
+
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => run(config).await,
+        Command::Export { format, output } => {
+            let db = open_offline(&config);
+            let format = format_for(format, output.as_deref());
+            let out: Box<dyn Write> = match output {
+                Some(ref path) => Box::new(File::create(path).unwrap_or_else(|e| fail(path, e))),
+                None => Box::new(io::stdout().lock()),
+            };
+            let count = transfer::export(&*db, format, out).unwrap_or_else(|e| {
+                eprintln!("hyperurl: export failed: {}", e);
+                process::exit(1);
+            });
+            eprintln!("exported {} links", count);
+        }
+        Command::Import { format, overwrite, input } => {
+            let db = open_offline(&config);
+            let format = format_for(format, input.as_deref());
+            let from: Box<dyn Read> = match input {
+                Some(ref path) => Box::new(File::open(path).unwrap_or_else(|e| fail(path, e))),
+                None => Box::new(io::stdin().lock()),
+            };
+            let report = transfer::import(&*db, format, from, &config.url_policy(), &config.alias_policy(), overwrite)
+                .unwrap_or_else(|e| {
+                    eprintln!("hyperurl: import failed: {}", e);
+                    process::exit(1);
+                });
+            for (line, reason) in &report.invalid {
+                eprintln!("line {}: {}", line, reason);
+            }
+            for (line, code) in &report.conflicts {
+                eprintln!("line {}: code `{}` already holds another link", line, code);
+            }
+            eprintln!(
+                "imported {}, unchanged {}, conflicts {}, invalid {}",
+                report.imported,
+                report.unchanged,
+                report.conflicts.len(),
+                report.invalid.len()
+            );
+            if !report.is_clean() {
+                process::exit(1);
+            }
+        }
+    }
+}
+
+async fn run(config: Config) {
+    let db = config.store.open().unwrap_or_else(|e| {
+        error!("cannot open store {}: {}", config.store, e);
+        process::exit(1);
+    });
+    info!("using {} store with {} links", config.store, db.len());
+    let app = Arc::new(App::new(config, db.clone()));
+    let config = app.config();
+    tokio::spawn(expiry::sweep(db, app.analytics(), config.sweep_every()));
 
-fn main() {
-    env::set_var("RUST_LOG", "hyperurl=info");
-    pretty_env_logger::init();
+    let addr = config.listen;
+    let listener = TcpListener::bind(addr).await.unwrap_or_else(|e| {
+        error!("cannot listen on {}: {}", addr, e);
+        process::exit(1);
+    });
+
+    #[cfg(feature = "tls")]
+    if let Some((cert, key)) = config.tls_files() {
+        let certs = Arc::new(Certificates::load(cert, key).unwrap_or_else(|e| {
+            error!("cannot load TLS certificate: {}", e);
+            process::exit(1);
+        }));
+        tokio::spawn(tls::reload_on_hangup(certs.clone()));
+        info!("URL shortener listening on https://{}, links under {}", addr, config.base_url);
+        serve_tls(listener, app.clone(), certs.acceptor(), shutdown_signal()).await;
+        info!("server stopped");
+        return;
+    }
+
+    info!("URL shortener listening on http://{}, links under {}", addr, config.base_url);
+    serve(listener, app.clone(), shutdown_signal()).await;
+    info!("server stopped");
+}
+
+/// Opens the store for export or import, which only make sense for a
+/// store that outlives the process.
+fn open_offline(config: &Config) -> Arc<dyn UrlStore> {
+    if config.store == StoreConfig::Memory {
+        eprintln!("hyperurl: the memory store starts empty every time, use --store file:<path>");
+        process::exit(2);
+    }
+    config.store.open().unwrap_or_else(|e| {
+        eprintln!("hyperurl: cannot open store {}: {}", config.store, e);
+        process::exit(1);
+    })
+}
+
+fn format_for(format: Option<Format>, path: Option<&Path>) -> Format {
+    format.unwrap_or(match path.and_then(Path::extension) {
+        Some(ext) if ext == "csv" => Format::Csv,
+        _ => Format::Jsonl,
+    })
+}
 
-    let addr = "127.0.0.1:3002".parse().unwrap();
-    let server = Server::bind(&addr)
-        .serve(|| service_fn(url_service))
-        .map_err(|e| error!("server error: {}", e));
-    info!("URL shortener listening on http://{}", addr);
-    rt::run(server);
+fn fail(path: &Path, e: io::Error) -> ! {
+    eprintln!("hyperurl: {}: {}", path.display(), e);
+    process::exit(1);
 }
@@ -0,0 +1,192 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use hyper::StatusCode;
+
+use crate::ratelimit::LimiterState;
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Upper bounds of the latency buckets, in seconds.
+const BUCKETS: [f64; 12] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0];
+
+/// Every route label, in output order; anything else counts as `unknown`.
+const ROUTES: [&str; 14] = [
+    "admin", "bulk", "healthz", "import", "link", "list_links", "metrics", "qr", "readyz", "redirect", "shorten", "stats",
+    "unknown", "version",
+];
+
+/// Status codes 100 to 999, all that `StatusCode` allows, one counter each.
+const STATUSES: usize = 900;
+
+/// Lock-free counters for one route; only a scrape reads them all.
+struct RouteStats {
+    statuses: [AtomicU64; STATUSES],
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    nanos: AtomicU64,
+}
+
+impl RouteStats {
+    fn new() -> Self {
+        RouteStats {
+            statuses: std::array::from_fn(|_| AtomicU64::new(0)),
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            count: AtomicU64::new(0),
+            nanos: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Process-wide counters, rendered in the Prometheus text format.
+pub struct Metrics {
+    routes: Vec<RouteStats>,
+    redirects: AtomicU64,
+    collisions: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            routes: ROUTES.iter().map(|_| RouteStats::new()).collect(),
+            redirects: AtomicU64::new(0),
+            collisions: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, route: &'static str, status: StatusCode, elapsed: Duration) {
+        let stats = &self.routes[route_index(route)];
+        stats.statuses[usize::from(status.as_u16()) - 100].fetch_add(1, Ordering::Relaxed);
+        let secs = elapsed.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|&le| secs <= le) {
+            stats.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        stats.count.fetch_add(1, Ordering::Relaxed);
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        stats.nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    pub fn redirected(&self) {
+        self.redirects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn collided(&self, collisions: u32) {
+        self.collisions.fetch_add(u64::from(collisions), Ordering::Relaxed);
+    }
+
+    /// Renders every metric; `links` and `limiter` are sampled by the caller.
+    pub fn render(&self, links: usize, limiter: &[LimiterState]) -> String {
+        let mut out = String::new();
+        // Routes never hit stay out, as they would with a map of labels.
+        let routes: Vec<_> = ROUTES
+            .iter()
+            .zip(&self.routes)
+            .filter(|(_, stats)| stats.count.load(Ordering::Relaxed) > 0)
+            .collect();
+
+        header(&mut out, "hyperurl_requests_total", "counter", "HTTP requests by route and status.");
+        for (route, stats) in &routes {
+            for (i, n) in stats.statuses.iter().enumerate() {
+                let n = n.load(Ordering::Relaxed);
+                if n > 0 {
+                    let status = i + 100;
+                    writeln!(out, "hyperurl_requests_total{{route=\"{}\",status=\"{}\"}} {}", route, status, n).unwrap();
+                }
+            }
+        }
+
+        header(&mut out, "hyperurl_request_duration_seconds", "histogram", "Time to produce a response.");
+        for (route, stats) in &routes {
+            let count = stats.count.load(Ordering::Relaxed);
+            let sum = stats.nanos.load(Ordering::Relaxed) as f64 / 1e9;
+            let mut cumulative = 0;
+            for (le, n) in BUCKETS.iter().zip(&stats.buckets) {
+                cumulative += n.load(Ordering::Relaxed);
+                writeln!(
+                    out,
+                    "hyperurl_request_duration_seconds_bucket{{route=\"{}\",le=\"{}\"}} {}",
+                    route, le, cumulative
+                )
+                .unwrap();
+            }
+            writeln!(out, "hyperurl_request_duration_seconds_bucket{{route=\"{}\",le=\"+Inf\"}} {}", route, count).unwrap();
+            writeln!(out, "hyperurl_request_duration_seconds_sum{{route=\"{}\"}} {}", route, sum).unwrap();
+            writeln!(out, "hyperurl_request_duration_seconds_count{{route=\"{}\"}} {}", route, count).unwrap();
+        }
+
+        header(&mut out, "hyperurl_links", "gauge", "Short links in the store.");
+        writeln!(out, "hyperurl_links {}", links).unwrap();
+        header(&mut out, "hyperurl_redirects_total", "counter", "Redirects served.");
+        writeln!(out, "hyperurl_redirects_total {}", self.redirects.load(Ordering::Relaxed)).unwrap();
+        header(&mut out, "hyperurl_code_collisions_total", "counter", "Generated codes that were already taken.");
+        writeln!(out, "hyperurl_code_collisions_total {}", self.collisions.load(Ordering::Relaxed)).unwrap();
+
+        header(&mut out, "hyperurl_rate_limit_allowed_total", "counter", "Requests let through by the rate limiter.");
+        for state in limiter {
+            writeln!(out, "hyperurl_rate_limit_allowed_total{{class=\"{}\"}} {}", state.class.name(), state.allowed).unwrap();
+        }
+        header(&mut out, "hyperurl_rate_limit_limited_total", "counter", "Requests refused with 429.");
+        for state in limiter {
+            writeln!(out, "hyperurl_rate_limit_limited_total{{class=\"{}\"}} {}", state.class.name(), state.limited).unwrap();
+        }
+        header(&mut out, "hyperurl_rate_limit_clients", "gauge", "Clients with a token bucket.");
+        for state in limiter {
+            writeln!(out, "hyperurl_rate_limit_clients{{class=\"{}\"}} {}", state.class.name(), state.tracked).unwrap();
+        }
+        out
+    }
+}
+
+fn route_index(route: &str) -> usize {
+    ROUTES.iter().position(|&r| r == route).unwrap_or_else(|| route_index("unknown"))
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ratelimit::Class;
+
+    #[test]
+    fn renders_counters_and_histograms() {
+        let metrics = Metrics::new();
+        metrics.observe("redirect", StatusCode::FOUND, Duration::from_millis(3));
+        metrics.observe("redirect", StatusCode::FOUND, Duration::from_millis(30));
+        metrics.observe("redirect", StatusCode::NOT_FOUND, Duration::from_secs(60));
+        metrics.observe("nonsense", StatusCode::NOT_FOUND, Duration::from_millis(1));
+        metrics.redirected();
+        metrics.collided(2);
+        let limiter = [LimiterState { class: Class::Create, tracked: 1, allowed: 4, limited: 1 }];
+
+        let text = metrics.render(7, &limiter);
+        for line in [
+            "hyperurl_requests_total{route=\"redirect\",status=\"302\"} 2",
+            "hyperurl_requests_total{route=\"redirect\",status=\"404\"} 1",
+            "hyperurl_request_duration_seconds_bucket{route=\"redirect\",le=\"0.001\"} 0",
+            "hyperurl_request_duration_seconds_bucket{route=\"redirect\",le=\"0.005\"} 1",
+            "hyperurl_request_duration_seconds_bucket{route=\"redirect\",le=\"0.05\"} 2",
+            "hyperurl_request_duration_seconds_bucket{route=\"redirect\",le=\"10\"} 2",
+            "hyperurl_request_duration_seconds_bucket{route=\"redirect\",le=\"+Inf\"} 3",
+            "hyperurl_request_duration_seconds_count{route=\"redirect\"} 3",
+            "hyperurl_links 7",
+            "hyperurl_redirects_total 1",
+            "hyperurl_code_collisions_total 2",
+            "hyperurl_rate_limit_limited_total{class=\"create\"} 1",
+            "# TYPE hyperurl_request_duration_seconds histogram",
+            "hyperurl_requests_total{route=\"unknown\",status=\"404\"} 1",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing `{}` in\n{}", line, text);
+        }
+        assert!(!text.contains("route=\"shorten\""), "{}", text);
+    }
+}
@@ -0,0 +1,142 @@
+use qrcode::render::svg;
+use qrcode::{Color, EcLevel, QrCode};
+use url::form_urlencoded;
+
+/// Light modules around the code, as the QR spec asks for.
+const QUIET_ZONE: usize = 4;
+const DEFAULT_SIZE: u32 = 256;
+const MAX_SIZE: u32 = 2048;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    Svg,
+    Png,
+}
+
+impl ImageFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ImageFormat::Svg => "image/svg+xml",
+            ImageFormat::Png => "image/png",
+        }
+    }
+}
+
+/// Query string of `GET /{code}/qr`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QrOptions {
+    pub format: ImageFormat,
+    /// Smallest acceptable width in pixels; modules are rounded up to whole
+    /// pixels so the image may come out slightly larger.
+    pub size: u32,
+    pub ecc: EcLevel,
+}
+
+impl QrOptions {
+    pub fn parse(query: &str) -> Result<Self, String> {
+        let mut out = QrOptions { format: ImageFormat::Svg, size: DEFAULT_SIZE, ecc: EcLevel::M };
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            match (&*key, &*value) {
+                ("format", "svg") => out.format = ImageFormat::Svg,
+                ("format", "png") => out.format = ImageFormat::Png,
+                ("format", other) => return Err(format!("unknown format `{}`, expected svg or png", other)),
+                ("size", size) => {
+                    out.size = size
+                        .parse()
+                        .ok()
+                        .filter(|n| (1..=MAX_SIZE).contains(n))
+                        .ok_or_else(|| format!("size must be between 1 and {}", MAX_SIZE))?
+                }
+                ("ecc", ecc) => {
+                    out.ecc = match ecc {
+                        "L" | "l" => EcLevel::L,
+                        "M" | "m" => EcLevel::M,
+                        "Q" | "q" => EcLevel::Q,
+                        "H" | "h" => EcLevel::H,
+                        _ => return Err(format!("unknown ecc `{}`, expected L, M, Q or H", ecc)),
+                    }
+                }
+                (key, _) => return Err(format!("unknown query parameter `{}`", key)),
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// Encodes `data` as a QR code image.
+pub fn render(data: &str, opts: &QrOptions) -> Result<Vec<u8>, String> {
+    let code = QrCode::with_error_correction_level(data, opts.ecc).map_err(|e| e.to_string())?;
+    match opts.format {
+        ImageFormat::Svg => Ok(code
+            .render::<svg::Color>()
+            .min_dimensions(opts.size, opts.size)
+            .build()
+            .into_bytes()),
+        ImageFormat::Png => png(&code, opts.size).map_err(|e| e.to_string()),
+    }
+}
+
+fn png(code: &QrCode, size: u32) -> Result<Vec<u8>, png::EncodingError> {
+    let modules = code.width() + 2 * QUIET_ZONE;
+    let scale = (size as usize).div_ceil(modules);
+    let side = modules * scale;
+    let colors = code.to_colors();
+
+    let mut pixels = vec![255u8; side * side];
+    for (i, color) in colors.iter().enumerate() {
+        if *color == Color::Light {
+            continue;
+        }
+        let (x, y) = (i % code.width() + QUIET_ZONE, i / code.width() + QUIET_ZONE);
+        for row in y * scale..(y + 1) * scale {
+            pixels[row * side + x * scale..row * side + (x + 1) * scale].fill(0);
+        }
+    }
+
+    let mut out = Vec::new();
+    let mut encoder = png::Encoder::new(&mut out, side as u32, side as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&pixels)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_options() {
+        assert_eq!(
+            QrOptions::parse("").unwrap(),
+            QrOptions { format: ImageFormat::Svg, size: DEFAULT_SIZE, ecc: EcLevel::M }
+        );
+        assert_eq!(
+            QrOptions::parse("format=png&size=100&ecc=H").unwrap(),
+            QrOptions { format: ImageFormat::Png, size: 100, ecc: EcLevel::H }
+        );
+        for bad in ["format=gif", "size=0", "size=99999", "ecc=X", "colour=red"] {
+            assert!(QrOptions::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn renders_png_at_least_requested_size() {
+        let opts = QrOptions { format: ImageFormat::Png, size: 100, ecc: EcLevel::L };
+        let image = render("https://u.rl/abcde", &opts).unwrap();
+        let decoder = png::Decoder::new(&image[..]);
+        let reader = decoder.read_info().unwrap();
+        let info = reader.info();
+        assert_eq!(info.width, info.height);
+        assert!(info.width >= 100);
+        assert_eq!(info.color_type, png::ColorType::Grayscale);
+    }
+
+    #[test]
+    fn renders_svg() {
+        let opts = QrOptions { format: ImageFormat::Svg, size: 64, ecc: EcLevel::Q };
+        let image = String::from_utf8(render("https://u.rl/abcde", &opts).unwrap()).unwrap();
+        assert!(image.starts_with("<?xml"));
+        assert!(image.contains("<svg"));
+    }
+}
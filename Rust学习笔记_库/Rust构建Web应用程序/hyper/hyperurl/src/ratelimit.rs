@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::auth::Principal;
+
+/// Most clients tracked per class. Reaching it drops buckets that have
+/// refilled completely, which behave exactly like a fresh one, then the
+/// least recently seen until a quarter of the room is free again, so the
+/// pass over the map runs once per that many new clients.
+const MAX_TRACKED: usize = 100_000;
+
+/// `count` requests per `per`, allowing bursts of up to `count`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum RateLimit {
+    Off,
+    Every { count: u32, per: Duration },
+}
+
+impl RateLimit {
+    fn per_second(count: u32, per: Duration) -> f64 {
+        f64::from(count) / per.as_secs_f64()
+    }
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "off" {
+            return Ok(RateLimit::Off);
+        }
+        let expected = || format!("unknown rate limit `{}`, expected `off` or `<count>/<s|m|h>`", s);
+        let (count, unit) = s.split_once('/').ok_or_else(expected)?;
+        let count = count.parse().ok().filter(|&n| n > 0).ok_or_else(expected)?;
+        let per = match unit {
+            "s" => Duration::from_secs(1),
+            "m" => Duration::from_secs(60),
+            "h" => Duration::from_secs(3600),
+            _ => return Err(expected()),
+        };
+        Ok(RateLimit::Every { count, per })
+    }
+}
+
+impl TryFrom<String> for RateLimit {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RateLimit::Off => write!(f, "off"),
+            RateLimit::Every { count, per } => {
+                let unit = match per.as_secs() {
+                    1 => "s",
+                    60 => "m",
+                    _ => "h",
+                };
+                write!(f, "{}/{}", count, unit)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    Create,
+    /// One token per item of a bulk request.
+    Bulk,
+    Redirect,
+}
+
+impl Class {
+    pub fn name(self) -> &'static str {
+        match self {
+            Class::Create => "create",
+            Class::Bulk => "bulk",
+            Class::Redirect => "redirect",
+        }
+    }
+}
+
+/// Counters for one class of requests.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LimiterState {
+    pub class: Class,
+    pub tracked: usize,
+    pub allowed: u64,
+    pub limited: u64,
+}
+
+/// Token buckets per client, one set each for link creation, bulk items
+/// and redirects. Callers with an API key are counted by owner, everyone else
+/// by address.
+pub struct RateLimiter {
+    create: Buckets,
+    bulk: Buckets,
+    redirect: Buckets,
+}
+
+impl RateLimiter {
+    pub fn new(create: RateLimit, bulk: RateLimit, redirect: RateLimit) -> Self {
+        RateLimiter { create: Buckets::new(create), bulk: Buckets::new(bulk), redirect: Buckets::new(redirect) }
+    }
+
+    /// Takes one token from the caller's bucket, or says how long until one
+    /// is available.
+    pub fn check(&self, class: Class, caller: Option<&Principal>, ip: IpAddr) -> Result<(), Duration> {
+        let key = match caller {
+            Some(p) => format!("key:{}", p.owner),
+            None => format!("ip:{}", ip),
+        };
+        self.buckets(class).take(&key, Instant::now())
+    }
+
+    pub fn state(&self) -> Vec<LimiterState> {
+        [Class::Create, Class::Bulk, Class::Redirect]
+            .into_iter()
+            .map(|class| {
+                let buckets = self.buckets(class);
+                LimiterState {
+                    class,
+                    tracked: buckets.clients.lock().unwrap().len(),
+                    allowed: buckets.allowed.load(Ordering::Relaxed),
+                    limited: buckets.limited.load(Ordering::Relaxed),
+                }
+            })
+            .collect()
+    }
+
+    fn buckets(&self, class: Class) -> &Buckets {
+        match class {
+            Class::Create => &self.create,
+            Class::Bulk => &self.bulk,
+            Class::Redirect => &self.redirect,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn is_full(&self, now: Instant, capacity: f64, rate: f64) -> bool {
+        self.tokens + now.saturating_duration_since(self.updated).as_secs_f64() * rate >= capacity
+    }
+
+    fn refill(&mut self, now: Instant, capacity: f64, rate: f64) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(capacity);
+        self.updated = now;
+    }
+}
+
+struct Buckets {
+    limit: RateLimit,
+    max_tracked: usize,
+    clients: Mutex<HashMap<String, Bucket>>,
+    allowed: AtomicU64,
+    limited: AtomicU64,
+}
+
+impl Buckets {
+    fn new(limit: RateLimit) -> Self {
+        Self::bounded(limit, MAX_TRACKED)
+    }
+
+    fn bounded(limit: RateLimit, max_tracked: usize) -> Self {
+        Buckets {
+            limit,
+            max_tracked,
+            clients: Mutex::default(),
+            allowed: AtomicU64::new(0),
+            limited: AtomicU64::new(0),
+        }
+    }
+
+    fn take(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let (capacity, rate) = match self.limit {
+            RateLimit::Off => {
+                self.allowed.fetch_add(1, Ordering::Relaxed);
+                return Ok(());
+            }
+            RateLimit::Every { count, per } => (f64::from(count), RateLimit::per_second(count, per)),
+        };
+
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= self.max_tracked && !clients.contains_key(key) {
+            evict(&mut clients, self.max_tracked * 3 / 4, now, capacity, rate);
+        }
+        let bucket = clients
+            .entry(key.to_string())
+            .or_insert(Bucket { tokens: capacity, updated: now });
+        bucket.refill(now, capacity, rate);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            self.allowed.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        } else {
+            self.limited.fetch_add(1, Ordering::Relaxed);
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+}
+
+/// Shrinks `clients` to at most `keep`, full buckets first, then the least
+/// recently seen ones. `updated` doubles as last seen, as every `take`
+/// refills.
+fn evict(clients: &mut HashMap<String, Bucket>, keep: usize, now: Instant, capacity: f64, rate: f64) {
+    clients.retain(|_, bucket| !bucket.is_full(now, capacity, rate));
+    if clients.len() <= keep {
+        return;
+    }
+    let mut seen: Vec<Instant> = clients.values().map(|bucket| bucket.updated).collect();
+    let drop = seen.len() - keep;
+    let (_, &mut newest_dropped, _) = seen.select_nth_unstable(drop - 1);
+    clients.retain(|_, bucket| bucket.updated > newest_dropped);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rate_limit() {
+        let per_minute = RateLimit::Every { count: 30, per: Duration::from_secs(60) };
+        assert_eq!("30/m".parse(), Ok(per_minute));
+        assert_eq!(per_minute.to_string(), "30/m");
+        assert_eq!("off".parse(), Ok(RateLimit::Off));
+        for bad in ["", "30", "0/s", "30/d", "-1/s"] {
+            assert!(bad.parse::<RateLimit>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let buckets = Buckets::new("2/s".parse().unwrap());
+        let start = Instant::now();
+        assert!(buckets.take("ip:1", start).is_ok());
+        assert!(buckets.take("ip:1", start).is_ok());
+        let wait = buckets.take("ip:1", start).unwrap_err();
+        assert_eq!(wait, Duration::from_millis(500));
+
+        // Other clients have their own bucket.
+        assert!(buckets.take("ip:2", start).is_ok());
+        assert!(buckets.take("ip:1", start + Duration::from_millis(500)).is_ok());
+        assert!(buckets.take("ip:1", start + Duration::from_millis(500)).is_err());
+        assert_eq!((buckets.allowed.load(Ordering::Relaxed), buckets.limited.load(Ordering::Relaxed)), (4, 2));
+    }
+
+    #[test]
+    fn tracked_clients_stay_bounded() {
+        let buckets = Buckets::bounded("2/h".parse().unwrap(), 8);
+        let start = Instant::now();
+        buckets.take("ip:hot", start).unwrap();
+        buckets.take("ip:hot", start).unwrap();
+        // A client rotating addresses never pushes the map past its bound,
+        // nor evicts a client that keeps coming back.
+        for n in 1..=100 {
+            let now = start + Duration::from_millis(n);
+            buckets.take(&format!("ip:10.0.0.{}", n), now).unwrap();
+            assert!(buckets.take("ip:hot", now).is_err());
+            assert!(buckets.clients.lock().unwrap().len() <= 8);
+        }
+        assert!(buckets.clients.lock().unwrap().contains_key("ip:10.0.0.100"));
+    }
+
+    #[test]
+    fn keys_and_addresses_are_separate_clients() {
+        let limiter = RateLimiter::new("1/h".parse().unwrap(), RateLimit::Off, RateLimit::Off);
+        let ip: IpAddr = [127, 0, 0, 1].into();
+        let alice = Principal { owner: "alice".into(), admin: false };
+
+        assert!(limiter.check(Class::Create, None, ip).is_ok());
+        assert!(limiter.check(Class::Create, None, ip).is_err());
+        assert!(limiter.check(Class::Create, Some(&alice), ip).is_ok());
+        for _ in 0..10 {
+            assert!(limiter.check(Class::Redirect, None, ip).is_ok());
+        }
+
+        let state = limiter.state();
+        assert_eq!(state[0], LimiterState { class: Class::Create, tracked: 2, allowed: 2, limited: 1 });
+        assert_eq!(state[2], LimiterState { class: Class::Redirect, tracked: 0, allowed: 10, limited: 0 });
+    }
+}
@@ -0,0 +1,153 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::rt::{TokioIo, TokioTimer};
+use hyper_util::server::graceful::GracefulShutdown;
+use log::{debug, error, info, warn};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+#[cfg(feature = "tls")]
+use tokio_rustls::TlsAcceptor;
+
+use crate::service::{url_service, App};
+
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Serves `app` on `listener` until `shutdown` resolves, then stops
+/// accepting and waits for in-flight requests to finish.
+pub async fn serve<F>(listener: TcpListener, app: Arc<App>, shutdown: F)
+where
+    F: Future<Output = ()>,
+{
+    accept_loop(listener, app, Transport::Plain, shutdown).await
+}
+
+/// Like `serve`, but every connection starts with a TLS handshake through
+/// `acceptor`.
+#[cfg(feature = "tls")]
+pub async fn serve_tls<F>(listener: TcpListener, app: Arc<App>, acceptor: TlsAcceptor, shutdown: F)
+where
+    F: Future<Output = ()>,
+{
+    accept_loop(listener, app, Transport::Tls(acceptor), shutdown).await
+}
+
+enum Transport {
+    Plain,
+    #[cfg(feature = "tls")]
+    Tls(TlsAcceptor),
+}
+
+async fn accept_loop<F>(listener: TcpListener, app: Arc<App>, transport: Transport, shutdown: F)
+where
+    F: Future<Output = ()>,
+{
+    let graceful = GracefulShutdown::new();
+    // Flips once `shutdown` resolves, for work that happens before a
+    // connection is handed to `graceful`.
+    #[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+    let (stop, stopping) = watch::channel(false);
+    tokio::pin!(shutdown);
+    let mut http = http1::Builder::new();
+    // Also bounds how long an idle keep-alive connection may linger.
+    let header_timeout = app.config().header_read_timeout();
+    http.timer(TokioTimer::new()).header_read_timeout(header_timeout);
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, peer) = match accepted {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        // Usually fd exhaustion; back off instead of spinning.
+                        error!("accept error: {}", e);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+                let app = app.clone();
+                let service = service_fn(move |req| url_service(req, app.clone(), peer));
+                match transport {
+                    Transport::Plain => {
+                        let conn = graceful.watch(http.serve_connection(TokioIo::new(stream), service));
+                        tokio::spawn(async move {
+                            if let Err(e) = conn.await {
+                                debug!("connection from {} failed: {}", peer, e);
+                            }
+                        });
+                    }
+                    #[cfg(feature = "tls")]
+                    Transport::Tls(ref acceptor) => {
+                        // The handshake runs on the connection's own task so
+                        // a slow client cannot hold up the accept loop. The
+                        // watcher is taken now so draining waits for the task,
+                        // and a handshake still going at shutdown is dropped:
+                        // no request has been sent on it yet.
+                        let handshake = tokio::time::timeout(header_timeout, acceptor.accept(stream));
+                        let (http, watcher) = (http.clone(), graceful.watcher());
+                        let mut stopping = stopping.clone();
+                        tokio::spawn(async move {
+                            let handshake = tokio::select! {
+                                handshake = handshake => handshake,
+                                _ = stopping.wait_for(|&stop| stop) => {
+                                    return debug!("TLS handshake with {} cut off by shutdown", peer);
+                                }
+                            };
+                            let stream = match handshake {
+                                Ok(Ok(stream)) => stream,
+                                Ok(Err(e)) => return debug!("TLS handshake with {} failed: {}", peer, e),
+                                Err(_) => return debug!("TLS handshake with {} timed out", peer),
+                            };
+                            let conn = watcher.watch(http.serve_connection(TokioIo::new(stream), service));
+                            if let Err(e) = conn.await {
+                                debug!("connection from {} failed: {}", peer, e);
+                            }
+                        });
+                    }
+                }
+            }
+            _ = &mut shutdown => break,
+        }
+    }
+
+    drop(listener);
+    stop.send_replace(true);
+    info!("shutting down, draining {} connections", graceful.count());
+    if tokio::time::timeout(DRAIN_TIMEOUT, graceful.shutdown()).await.is_err() {
+        warn!("gave up draining connections after {:?}", DRAIN_TIMEOUT);
+    }
+}
+
+/// Resolves on SIGINT, or SIGTERM on unix.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("cannot listen for ctrl-c: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                term.recv().await;
+            }
+            Err(e) => {
+                error!("cannot listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
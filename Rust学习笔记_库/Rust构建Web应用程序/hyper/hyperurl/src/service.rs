@@ -1,30 +1,508 @@
-use std::sync::RwLock;
-use std::collections::HashMap;
-use std::sync::{Arc};
-use std::str;
-use hyper::Request;
-use hyper::{Body, Response};
-use hyper::rt::{Future, Stream};
-
-use lazy_static::lazy_static;
-
-use crate::shortener::shorten_url;
-
-type UrlDb = Arc<RwLock<HashMap<String, String>>>;
-type BoxFut = Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>;
-
-lazy_static! {
-    static ref SHORT_URLS: UrlDb = Arc::new(RwLock::new(HashMap::new()));
-}
-
-pub(crate) fn url_service(req: Request<Body>) -> BoxFut {
-    let reply = req.into_body().concat2().map(move |chunk| {
-        let c = chunk.iter().cloned().collect::<Vec<u8>>();
-        let url_to_shorten = str::from_utf8(&c).unwrap();
-        let shortened_url = shorten_url(url_to_shorten);
-        SHORT_URLS.write().unwrap().insert(shortened_url, url_to_shorten.to_string());
-        let a = &*SHORT_URLS.read().unwrap();
-        Response::new(Body::from(format!("{:#?}", a)))
-    });
-    Box::new(reply)
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use bytes::Bytes;
+use chrono::Utc;
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::body::Incoming;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper::header::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, LOCATION};
+use serde::Serialize;
+
+use crate::analytics::Analytics;
+use crate::api::{
+    parse_bulk, BulkOutcome, BulkResponse, BulkResult, ImportResponse, LinkInfo, LinkPage, ListQuery,
+    ShortenRequest, ShortenResponse, UpdateRequest,
+};
+use crate::auth::{Keys, Principal};
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::metrics::{self, Metrics};
+#[cfg(feature = "qr")]
+use crate::qr::{self, QrOptions};
+use crate::ratelimit::{Class, RateLimiter};
+use crate::shortener::{claim_alias, expiry, shorten_url, CodeGenerator, InvalidExpiry, Shortened};
+use crate::store::{Link, LinkFilter, UrlStore};
+use crate::transfer::{self, Format};
+use crate::validate::{AliasPolicy, UrlPolicy};
+
+pub type Body = Full<Bytes>;
+type ApiResult = Result<Response<Body>, ApiError>;
+
+pub struct App {
+    config: Config,
+    db: Arc<dyn UrlStore>,
+    codes: Box<dyn CodeGenerator>,
+    policy: UrlPolicy,
+    aliases: AliasPolicy,
+    keys: Keys,
+    limiter: RateLimiter,
+    analytics: Arc<Analytics>,
+    metrics: Metrics,
+}
+
+impl App {
+    pub fn new(config: Config, db: Arc<dyn UrlStore>) -> Self {
+        let codes = config.codes.generator(config.code_length, &*db);
+        let policy = config.url_policy();
+        let aliases = config.alias_policy();
+        let keys = Keys::new(&config.api_keys);
+        let limiter = config.rate_limiter();
+        App {
+            config,
+            db,
+            codes,
+            policy,
+            aliases,
+            keys,
+            limiter,
+            analytics: Arc::new(Analytics::new()),
+            metrics: Metrics::new(),
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Click counters, for the expiry sweep to forget removed codes.
+    pub fn analytics(&self) -> Arc<Analytics> {
+        self.analytics.clone()
+    }
+}
+
+pub async fn url_service(
+    req: Request<Incoming>,
+    app: Arc<App>,
+    peer: SocketAddr,
+) -> Result<Response<Body>, Infallible> {
+    let started = Instant::now();
+    let name = route_name(req.uri().path());
+    let res = route(req, &app, peer).await.unwrap_or_else(ApiError::into_response);
+    app.metrics.observe(name, res.status(), started.elapsed());
+    Ok(res)
+}
+
+/// Label for the metrics; codes are folded so the label set stays small.
+fn route_name(path: &str) -> &'static str {
+    match path {
+        "/shorten" => "shorten",
+        "/metrics" => "metrics",
+        "/healthz" => "healthz",
+        "/readyz" => "readyz",
+        "/version" => "version",
+        "/admin/compact" | "/admin/snapshot" => "admin",
+        "/api/bulk" => "bulk",
+        "/api/import" => "import",
+        "/api/links" => "list_links",
+        _ if path.starts_with("/api/links/") => "link",
+        _ => match code_route(path) {
+            Some((_, None)) => "redirect",
+            Some((_, Some("stats"))) => "stats",
+            #[cfg(feature = "qr")]
+            Some((_, Some("qr"))) => "qr",
+            _ => "unknown",
+        },
+    }
+}
+
+async fn route(req: Request<Incoming>, app: &Arc<App>, peer: SocketAddr) -> ApiResult {
+    let path = req.uri().path().to_owned();
+    match (req.method(), path.as_str()) {
+        (&Method::POST, "/shorten") => {
+            let caller = app.keys.authenticate(req.headers())?;
+            app.limiter
+                .check(Class::Create, caller.as_ref(), peer.ip())
+                .map_err(ApiError::RateLimited)?;
+            let body = read_body(req, app).await?;
+            blocking(app, move |app| shorten(&body, caller, app)).await
+        }
+        (_, "/shorten") => Err(ApiError::MethodNotAllowed("POST")),
+        (&Method::POST, "/api/bulk") => {
+            let caller = app.keys.authenticate(req.headers())?;
+            let body = read_body(req, app).await?;
+            blocking(app, move |app| bulk(&body, caller, peer, app)).await
+        }
+        (_, "/api/bulk") => Err(ApiError::MethodNotAllowed("POST")),
+        (&Method::POST, "/api/import") => {
+            let caller = app.keys.require(req.headers())?;
+            // Imported links keep whatever owner the file names.
+            if !caller.admin {
+                return Err(ApiError::Forbidden("admin key required"));
+            }
+            let csv = req.headers().get(CONTENT_TYPE).is_some_and(|v| v.as_bytes().starts_with(b"text/csv"));
+            let format = if csv { Format::Csv } else { Format::Jsonl };
+            let body = read_body(req, app).await?;
+            blocking(app, move |app| import(&body, format, app)).await
+        }
+        (_, "/api/import") => Err(ApiError::MethodNotAllowed("POST")),
+        (&Method::GET, "/metrics") => Ok(render_metrics(app)),
+        (&Method::GET, "/healthz") => Ok(json(StatusCode::OK, &Health { status: "ok" })),
+        (&Method::GET, "/readyz") => blocking(app, ready).await,
+        (&Method::GET, "/version") => Ok(json(StatusCode::OK, &BuildInfo::current())),
+        (_, "/metrics" | "/healthz" | "/readyz" | "/version") => Err(ApiError::MethodNotAllowed("GET")),
+        (&Method::POST, "/admin/compact" | "/admin/snapshot") => {
+            let caller = app.keys.require(req.headers())?;
+            if !caller.admin {
+                return Err(ApiError::Forbidden("admin key required"));
+            }
+            maintain(&path, app).await
+        }
+        (_, "/admin/compact" | "/admin/snapshot") => Err(ApiError::MethodNotAllowed("POST")),
+        (&Method::GET, "/api/links") => list(&req, app),
+        (_, "/api/links") => Err(ApiError::MethodNotAllowed("GET")),
+        (_, path) if path.starts_with("/api/links/") => {
+            let code = &path["/api/links/".len()..];
+            if code.is_empty() || code.contains('/') {
+                return Err(ApiError::NotFound);
+            }
+            manage(req, code, app).await
+        }
+        (method, path) => match code_route(path) {
+            Some((code, None)) if method == Method::GET => redirect(code, &req, app, peer),
+            Some((code, Some("stats"))) if method == Method::GET => stats(code, &req, app),
+            #[cfg(feature = "qr")]
+            Some((code, Some("qr"))) if method == Method::GET => qr_code(code, &req, app),
+            #[cfg(feature = "qr")]
+            Some((_, Some("qr"))) => Err(ApiError::MethodNotAllowed("GET")),
+            Some((_, None | Some("stats"))) => Err(ApiError::MethodNotAllowed("GET")),
+            _ => Err(ApiError::NotFound),
+        },
+    }
+}
+
+/// Runs a handler that writes to the store on a blocking thread: file-backed
+/// stores fsync every write and now and then compact inline.
+async fn blocking<F>(app: &Arc<App>, handler: F) -> ApiResult
+where
+    F: FnOnce(&App) -> ApiResult + Send + 'static,
+{
+    let app = app.clone();
+    tokio::task::spawn_blocking(move || handler(&app))
+        .await
+        .map_err(|e| ApiError::Store(std::io::Error::other(e)))?
+}
+
+/// Buffers the body up to `max_body_size`. A declared length over the limit
+/// is refused before reading anything; otherwise reading stops as soon as
+/// the limit is passed.
+async fn read_body(req: Request<Incoming>, app: &App) -> Result<Bytes, ApiError> {
+    let limit = app.config.max_body_size;
+    let declared = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if declared.is_some_and(|len| len > limit as u64) {
+        return Err(ApiError::PayloadTooLarge(limit));
+    }
+
+    let body = Limited::new(req.into_body(), limit).collect();
+    match tokio::time::timeout(app.config.body_read_timeout(), body).await {
+        Err(_) => Err(ApiError::RequestTimeout),
+        Ok(Err(e)) if e.is::<LengthLimitError>() => Err(ApiError::PayloadTooLarge(limit)),
+        Ok(Err(e)) => Err(ApiError::BadRequest(e.to_string())),
+        Ok(Ok(body)) => Ok(body.to_bytes()),
+    }
+}
+
+fn shorten(body: &[u8], caller: Option<Principal>, app: &App) -> ApiResult {
+    let req: ShortenRequest =
+        serde_json::from_slice(body).map_err(|e| ApiError::BadRequest(e.to_string()))?;
+    let shortened = shorten_one(req, caller.map(|p| p.owner), app)?;
+
+    let status = if shortened.created { StatusCode::CREATED } else { StatusCode::OK };
+    Ok(json(status, &ShortenResponse {
+        short_url: app.config.short_url(&shortened.code),
+        code: shortened.code,
+        url: shortened.link.url,
+        created_at: shortened.link.created_at,
+        expires_at: shortened.link.expires_at,
+    }))
+}
+
+fn shorten_one(req: ShortenRequest, owner: Option<String>, app: &App) -> Result<Shortened, ApiError> {
+    let url = app.policy.normalize(&req.url)?;
+    let expires_at = expiry(req.ttl, req.expires_at, Utc::now())?;
+    let link = Link::new(url).expiring(expires_at).owned_by(owner);
+    let shortened = match req.custom_alias {
+        Some(alias) => claim_alias(&*app.db, &app.aliases, alias, link)?,
+        None => shorten_url(&*app.db, &*app.codes, &app.aliases, link)?,
+    };
+    app.metrics.collided(shortened.collisions);
+    Ok(shortened)
+}
+
+/// Shortens every item on its own against the bulk budget, one token per
+/// item; failures, including running out of budget part way, are reported
+/// per item, rate limited ones with a `retry_after`.
+fn bulk(body: &[u8], caller: Option<Principal>, peer: SocketAddr, app: &App) -> ApiResult {
+    let items = parse_bulk(body).map_err(ApiError::BadRequest)?;
+    let mut res = BulkResponse { created: 0, existing: 0, failed: 0, results: Vec::with_capacity(items.len()) };
+    for (index, item) in items.into_iter().enumerate() {
+        let outcome = item.map_err(ApiError::BadRequest).and_then(|req| {
+            app.limiter
+                .check(Class::Bulk, caller.as_ref(), peer.ip())
+                .map_err(ApiError::RateLimited)?;
+            shorten_one(req, caller.as_ref().map(|p| p.owner.clone()), app)
+        });
+        let outcome = match outcome {
+            Ok(shortened) => {
+                if shortened.created {
+                    res.created += 1;
+                } else {
+                    res.existing += 1;
+                }
+                BulkOutcome::Shortened {
+                    link: link_info(shortened.code, shortened.link, app),
+                    created: shortened.created,
+                }
+            }
+            Err(e) => {
+                res.failed += 1;
+                BulkOutcome::Failed(e.body())
+            }
+        };
+        res.results.push(BulkResult { index, outcome });
+    }
+    Ok(json(StatusCode::OK, &res))
+}
+
+/// Loads `export` output like `hyperurl import` does: codes, owners and
+/// dates are kept, and problems are reported per line.
+fn import(body: &[u8], format: Format, app: &App) -> ApiResult {
+    // Anything `transfer::import` fails on besides this is the store's fault.
+    std::str::from_utf8(body).map_err(|e| ApiError::BadRequest(e.to_string()))?;
+    let report = transfer::import(&*app.db, format, body, &app.policy, &app.aliases, false)?;
+    Ok(json(StatusCode::OK, &ImportResponse::from(report)))
+}
+
+fn redirect(code: &str, req: &Request<Incoming>, app: &App, peer: SocketAddr) -> ApiResult {
+    app.limiter
+        .check(Class::Redirect, None, peer.ip())
+        .map_err(ApiError::RateLimited)?;
+    let link = app.db.get(code).ok_or(ApiError::NotFound)?;
+    if link.is_expired(Utc::now()) {
+        return Err(ApiError::Expired);
+    }
+    app.analytics.record(code, req.headers(), peer.ip());
+    app.metrics.redirected();
+    Ok(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(LOCATION, link.url.as_str())
+        .body(Body::default())
+        .unwrap())
+}
+
+/// Click totals of a link, counted since the server last started. Recent
+/// hits carry referrers and user agents, so only a caller whose key may
+/// change the link gets them.
+fn stats(code: &str, req: &Request<Incoming>, app: &App) -> ApiResult {
+    let link = app.db.get(code).ok_or(ApiError::NotFound)?;
+    let recent = req.headers().contains_key(AUTHORIZATION)
+        && app.keys.require(req.headers())?.can_modify(&link);
+    Ok(json(StatusCode::OK, &app.analytics.stats(code, recent)))
+}
+
+#[derive(Serialize)]
+struct Health {
+    status: &'static str,
+}
+
+fn ready(app: &App) -> ApiResult {
+    app.db.check().map_err(|e| ApiError::NotReady(format!("store is not writable: {}", e)))?;
+    Ok(json(StatusCode::OK, &Health { status: "ready" }))
+}
+
+/// Storage maintenance behind `/admin/*`, run on a blocking thread since
+/// both operations rewrite whole files.
+async fn maintain(path: &str, app: &App) -> ApiResult {
+    let db = app.db.clone();
+    let compact = path == "/admin/compact";
+    let done = tokio::task::spawn_blocking(move || {
+        if compact {
+            db.compact().map(|done| done.then(|| serde_json::json!({ "compacted": true })))
+        } else {
+            db.snapshot().map(|at| at.map(|at| serde_json::json!({ "snapshot": at })))
+        }
+    })
+    .await
+    .map_err(|e| ApiError::Store(std::io::Error::other(e)))??;
+    match done {
+        Some(body) => Ok(json(StatusCode::OK, &body)),
+        None if compact => Err(ApiError::Unsupported("compaction")),
+        None => Err(ApiError::Unsupported("snapshots")),
+    }
+}
+
+#[derive(Serialize)]
+struct BuildInfo {
+    name: &'static str,
+    version: &'static str,
+    features: Vec<&'static str>,
+}
+
+impl BuildInfo {
+    fn current() -> Self {
+        let features = [("qr", cfg!(feature = "qr")), ("tls", cfg!(feature = "tls"))];
+        BuildInfo {
+            name: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+            features: features.iter().filter(|(_, on)| *on).map(|(name, _)| *name).collect(),
+        }
+    }
+}
+
+#[cfg(feature = "qr")]
+fn qr_code(code: &str, req: &Request<Incoming>, app: &App) -> ApiResult {
+    let opts = QrOptions::parse(req.uri().query().unwrap_or("")).map_err(ApiError::BadRequest)?;
+    let link = app.db.get(code).ok_or(ApiError::NotFound)?;
+    if link.is_expired(Utc::now()) {
+        return Err(ApiError::Expired);
+    }
+    let image = qr::render(&app.config.short_url(code), &opts).map_err(ApiError::BadRequest)?;
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, opts.format.content_type())
+        // The image depends only on the code, not on where it points.
+        .header(hyper::header::CACHE_CONTROL, "public, max-age=86400")
+        .body(Body::from(image))
+        .unwrap())
+}
+
+fn render_metrics(app: &App) -> Response<Body> {
+    let text = app.metrics.render(app.db.len(), &app.limiter.state());
+    Response::builder()
+        .header(CONTENT_TYPE, metrics::CONTENT_TYPE)
+        .body(Body::from(text))
+        .unwrap()
+}
+
+fn list(req: &Request<Incoming>, app: &App) -> ApiResult {
+    let caller = app.keys.require(req.headers())?;
+    let query = ListQuery::parse(req.uri().query().unwrap_or("")).map_err(ApiError::BadRequest)?;
+    // Only admins may look at other owners' links, or at everything at once.
+    let owner = match query.owner {
+        Some(owner) if caller.admin || owner == caller.owner => Some(owner),
+        Some(_) => return Err(ApiError::Forbidden("only an admin may list another owner's links")),
+        None if caller.admin => None,
+        None => Some(caller.owner),
+    };
+    let filter = LinkFilter { owner, prefix: query.prefix };
+
+    let mut links = app.db.page(query.cursor.as_deref(), query.limit + 1, &filter);
+    let next_cursor = if links.len() > query.limit {
+        links.truncate(query.limit);
+        links.last().map(|(code, _)| code.clone())
+    } else {
+        None
+    };
+    Ok(json(StatusCode::OK, &LinkPage {
+        links: links.into_iter().map(|(code, link)| link_info(code, link, app)).collect(),
+        next_cursor,
+    }))
+}
+
+async fn manage(req: Request<Incoming>, code: &str, app: &Arc<App>) -> ApiResult {
+    const ALLOWED: &str = "GET, PATCH, DELETE";
+    if ![Method::GET, Method::PATCH, Method::DELETE].contains(req.method()) {
+        return Err(ApiError::MethodNotAllowed(ALLOWED));
+    }
+    let caller = app.keys.require(req.headers())?;
+    match *req.method() {
+        Method::GET => {
+            let link = owned_link(code, &caller, app)?;
+            Ok(json(StatusCode::OK, &link_info(code.to_string(), link, app)))
+        }
+        Method::PATCH => {
+            let body = read_body(req, app).await?;
+            let code = code.to_string();
+            blocking(app, move |app| update(&code, &body, &caller, app)).await
+        }
+        _ => {
+            let code = code.to_string();
+            blocking(app, move |app| delete(&code, &caller, app)).await
+        }
+    }
+}
+
+fn owned_link(code: &str, caller: &Principal, app: &App) -> Result<Link, ApiError> {
+    let link = app.db.get(code).ok_or(ApiError::NotFound)?;
+    if !caller.can_modify(&link) {
+        return Err(ApiError::Forbidden("only the owner or an admin may change this link"));
+    }
+    Ok(link)
+}
+
+fn update(code: &str, body: &[u8], caller: &Principal, app: &App) -> ApiResult {
+    let req: UpdateRequest =
+        serde_json::from_slice(body).map_err(|e| ApiError::BadRequest(e.to_string()))?;
+    let url = req.url.as_deref().map(|url| app.policy.normalize(url)).transpose()?;
+    let now = Utc::now();
+    let expires_at = match (req.ttl, req.expires_at) {
+        (None, None) if url.is_none() => return Err(ApiError::BadRequest("nothing to update".into())),
+        (None, None) => None,
+        (Some(_), Some(_)) => return Err(InvalidExpiry::Both.into()),
+        (None, Some(None)) => Some(None),
+        (ttl, at) => Some(expiry(ttl, at.flatten(), now)?),
+    };
+
+    // Retry if the link changed between reading and writing it.
+    loop {
+        let current = owned_link(code, caller, app)?;
+        let link = Link {
+            url: url.clone().unwrap_or_else(|| current.url.clone()),
+            expires_at: expires_at.unwrap_or(current.expires_at),
+            ..current.clone()
+        };
+        if app.db.compare_and_swap(code, &current, Some(link.clone()))? {
+            return Ok(json(StatusCode::OK, &link_info(code.to_string(), link, app)));
+        }
+    }
+}
+
+fn delete(code: &str, caller: &Principal, app: &App) -> ApiResult {
+    loop {
+        let current = owned_link(code, caller, app)?;
+        if app.db.compare_and_swap(code, &current, None)? {
+            app.analytics.forget(code);
+            return Ok(Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Body::default())
+                .unwrap());
+        }
+    }
+}
+
+fn link_info(code: String, link: Link, app: &App) -> LinkInfo {
+    LinkInfo {
+        short_url: app.config.short_url(&code),
+        code,
+        url: link.url,
+        created_at: link.created_at,
+        expires_at: link.expires_at,
+        owner: link.owner,
+    }
+}
+
+/// Splits `/{code}` and `/{code}/{action}`.
+fn code_route(path: &str) -> Option<(&str, Option<&str>)> {
+    let rest = path.strip_prefix('/')?;
+    let (code, action) = match rest.split_once('/') {
+        Some((code, action)) => (code, Some(action)),
+        None => (rest, None),
+    };
+    if code.is_empty() || action.is_some_and(|a| a.is_empty() || a.contains('/')) {
+        None
+    } else {
+        Some((code, action))
+    }
+}
+
+fn json<T: Serialize>(status: StatusCode, body: &T) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(body).unwrap()))
+        .unwrap()
 }
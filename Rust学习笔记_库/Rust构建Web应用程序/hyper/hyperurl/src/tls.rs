@@ -0,0 +1,142 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use log::{error, info};
+use tokio_rustls::rustls::crypto::{ring, CryptoProvider};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+/// Serves the certificate last loaded from `cert` and `key`. A reload that
+/// fails keeps the previous certificate, so a half-written file never takes
+/// the server down.
+pub struct Certificates {
+    cert: PathBuf,
+    key: PathBuf,
+    provider: Arc<CryptoProvider>,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl Certificates {
+    pub fn load(cert: &Path, key: &Path) -> io::Result<Self> {
+        let provider = Arc::new(ring::default_provider());
+        let current = read(cert, key, &provider)?;
+        Ok(Certificates {
+            cert: cert.to_path_buf(),
+            key: key.to_path_buf(),
+            provider,
+            current: RwLock::new(Arc::new(current)),
+        })
+    }
+
+    /// Rereads both files; new handshakes use the result.
+    pub fn reload(&self) -> io::Result<()> {
+        let fresh = read(&self.cert, &self.key, &self.provider)?;
+        *self.current.write().unwrap() = Arc::new(fresh);
+        Ok(())
+    }
+
+    /// Acceptor for HTTP/1.1 over TLS that picks up every reload.
+    pub fn acceptor(self: &Arc<Self>) -> TlsAcceptor {
+        let mut config = ServerConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()
+            .expect("ring supports the default protocol versions")
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        TlsAcceptor::from(Arc::new(config))
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+impl fmt::Debug for Certificates {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Certificates").field("cert", &self.cert).field("key", &self.key).finish()
+    }
+}
+
+fn read(cert: &Path, key: &Path, provider: &CryptoProvider) -> io::Result<CertifiedKey> {
+    let chain = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| io::Error::other(format!("{}: {}", cert.display(), e)))?;
+    if chain.is_empty() {
+        return Err(io::Error::other(format!("{}: no certificates found", cert.display())));
+    }
+    let key_der = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| io::Error::other(format!("{}: {}", key.display(), e)))?;
+    CertifiedKey::from_der(chain, key_der, provider)
+        .map_err(|e| io::Error::other(format!("{}: {}", key.display(), e)))
+}
+
+/// Reloads `certs` on every SIGHUP. Never resolves.
+pub async fn reload_on_hangup(certs: Arc<Certificates>) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                error!("cannot listen for SIGHUP: {}", e);
+                return std::future::pending().await;
+            }
+        };
+        while hangups.recv().await.is_some() {
+            match certs.reload() {
+                Ok(()) => info!("reloaded TLS certificate from {}", certs.cert.display()),
+                Err(e) => error!("keeping the old TLS certificate: {}", e),
+            }
+        }
+    }
+    std::future::pending().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn write_pair(dir: &Path, name: &str) -> (PathBuf, PathBuf) {
+        let generated = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        fs::write(&cert, generated.cert.pem()).unwrap();
+        fs::write(&key, generated.signing_key.serialize_pem()).unwrap();
+        (cert, key)
+    }
+
+    #[test]
+    fn failed_reloads_keep_the_old_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = write_pair(dir.path(), "localhost");
+        let certs = Certificates::load(&cert, &key).unwrap();
+        let first = certs.current.read().unwrap().clone();
+
+        fs::write(&key, "not a key").unwrap();
+        assert!(certs.reload().is_err());
+        assert!(Arc::ptr_eq(&first, &*certs.current.read().unwrap()));
+
+        write_pair(dir.path(), "example.test");
+        certs.reload().unwrap();
+        assert_ne!(first.cert, certs.current.read().unwrap().cert);
+    }
+
+    #[test]
+    fn mismatched_key_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = write_pair(dir.path(), "localhost");
+        let stale = fs::read(&cert).unwrap();
+        write_pair(dir.path(), "localhost");
+        fs::write(&cert, stale).unwrap();
+        assert!(Certificates::load(&cert, &key).is_err());
+        assert!(Certificates::load(&dir.path().join("missing.pem"), &key).is_err());
+    }
+}
@@ -0,0 +1,881 @@
+use std::future::Future;
+use std::io::{Read, Write};
+use std::net::{self, SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+
+use hyperurl::auth::ApiKey;
+use hyperurl::config::Config;
+use hyperurl::server::serve;
+#[cfg(feature = "tls")]
+use hyperurl::{server::serve_tls, tls::Certificates};
+use hyperurl::service::App;
+use hyperurl::store::{FileStore, MemoryStore, UrlStore};
+
+struct Reply {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl Reply {
+    fn json(&self) -> Value {
+        serde_json::from_str(&self.body).unwrap()
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+struct Server {
+    addr: SocketAddr,
+    stop: oneshot::Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl Server {
+    fn start() -> Self {
+        Server::with_config(Config::default())
+    }
+
+    fn with_config(config: Config) -> Self {
+        Server::with_store(config, Arc::new(MemoryStore::new()))
+    }
+
+    fn with_store(config: Config, db: Arc<dyn UrlStore>) -> Self {
+        let app = Arc::new(App::new(config, db));
+        Server::launch(move |listener, stopped| async move {
+            serve(listener, app, async {
+                let _ = stopped.await;
+            })
+            .await
+        })
+    }
+
+    #[cfg(feature = "tls")]
+    fn with_tls(config: Config, acceptor: tokio_rustls::TlsAcceptor) -> Self {
+        let app = Arc::new(App::new(config, Arc::new(MemoryStore::new())));
+        Server::launch(move |listener, stopped| async move {
+            serve_tls(listener, app, acceptor, async {
+                let _ = stopped.await;
+            })
+            .await
+        })
+    }
+
+    fn launch<F, Fut>(run: F) -> Self
+    where
+        F: FnOnce(TcpListener, oneshot::Receiver<()>) -> Fut + Send + 'static,
+        Fut: Future<Output = ()>,
+    {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = oneshot::channel::<()>();
+
+        let thread = thread::spawn(move || {
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            rt.block_on(async move {
+                let listener = TcpListener::from_std(listener).unwrap();
+                run(listener, stopped).await;
+            });
+        });
+        Server { addr, stop, thread }
+    }
+
+    fn shutdown(self) {
+        self.stop.send(()).unwrap();
+        self.thread.join().unwrap();
+    }
+}
+
+fn spawn_server() -> SocketAddr {
+    spawn_with(Config::default())
+}
+
+fn spawn_with(config: Config) -> SocketAddr {
+    let server = Server::with_config(config);
+    let addr = server.addr;
+    // Left running for the rest of the test process.
+    std::mem::forget(server);
+    addr
+}
+
+fn read_reply(mut stream: impl Read) -> Reply {
+    let mut raw = String::new();
+    stream.read_to_string(&mut raw).unwrap();
+
+    let (head, body) = raw.split_once("\r\n\r\n").unwrap();
+    let mut lines = head.lines();
+    let status = lines.next().unwrap().split(' ').nth(1).unwrap().parse().unwrap();
+    let headers = lines
+        .filter_map(|l| l.split_once(':'))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect();
+    Reply { status, headers, body: body.to_string() }
+}
+
+fn request<B: AsRef<[u8]>>(addr: SocketAddr, method: &str, path: &str, body: B) -> Reply {
+    request_with(addr, method, path, &[], body)
+}
+
+fn request_with<B: AsRef<[u8]>>(
+    addr: SocketAddr,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: B,
+) -> Reply {
+    let body = body.as_ref();
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let headers: String = headers.iter().map(|(k, v)| format!("{}: {}\r\n", k, v)).collect();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+        method,
+        path,
+        addr,
+        headers,
+        body.len()
+    )
+    .unwrap();
+    stream.write_all(body).unwrap();
+    read_reply(stream)
+}
+
+fn shorten(addr: SocketAddr, body: Value) -> Reply {
+    request(addr, "POST", "/shorten", body.to_string())
+}
+
+const ALICE: &str = "alice-secret-key-0001";
+const BOB: &str = "bob-secret-key-000001";
+const ADMIN: &str = "ops-secret-key-000001";
+
+fn spawn_keyed_server() -> SocketAddr {
+    let key = |owner: &str, key: &str| ApiKey { key: key.into(), owner: owner.into(), admin: owner == "ops" };
+    let config = Config {
+        api_keys: vec![key("alice", ALICE), key("bob", BOB), key("ops", ADMIN)],
+        ..Config::default()
+    };
+    spawn_with(config)
+}
+
+fn request_as<B: AsRef<[u8]>>(addr: SocketAddr, key: &str, method: &str, path: &str, body: B) -> Reply {
+    let auth = format!("Bearer {}", key);
+    request_with(addr, method, path, &[("Authorization", &auth)], body)
+}
+
+fn shorten_as(addr: SocketAddr, key: &str, body: Value) -> Reply {
+    request_as(addr, key, "POST", "/shorten", body.to_string())
+}
+
+fn codes(page: &Value) -> Vec<&str> {
+    page["links"].as_array().unwrap().iter().map(|l| l["code"].as_str().unwrap()).collect()
+}
+
+#[test]
+fn shorten_then_follow() {
+    let addr = spawn_server();
+    let created = shorten(addr, json!({ "url": "https://WWW.rust-lang.org:443/learn" }));
+    assert_eq!(created.status, 201);
+    assert_eq!(created.header("content-type"), Some("application/json"));
+
+    let body = created.json();
+    let code = body["code"].as_str().unwrap();
+    assert_eq!(body["short_url"], format!("https://u.rl/{}", code));
+    assert_eq!(body["url"], "https://www.rust-lang.org/learn");
+    assert!(body["created_at"].is_string());
+
+    let res = request(addr, "GET", &format!("/{}", code), "");
+    assert_eq!(res.status, 302);
+    assert_eq!(res.header("location"), Some("https://www.rust-lang.org/learn"));
+}
+
+#[test]
+fn shortening_twice_returns_same_code() {
+    let addr = spawn_server();
+    let first = shorten(addr, json!({ "url": "https://docs.rs/" })).json();
+    let again = shorten(addr, json!({ "url": "https://docs.rs/" }));
+    assert_eq!(again.status, 200);
+    assert_eq!(again.json()["code"], first["code"]);
+    assert_eq!(again.json()["created_at"], first["created_at"]);
+}
+
+#[test]
+fn custom_alias() {
+    let addr = spawn_server();
+    let res = shorten(addr, json!({ "url": "https://crates.io/", "custom_alias": "crates" }));
+    assert_eq!(res.status, 201);
+    assert_eq!(res.json()["code"], "crates");
+    assert_eq!(request(addr, "GET", "/crates", "").header("location"), Some("https://crates.io/"));
+
+    let res = shorten(addr, json!({ "url": "https://example.com/", "custom_alias": "crates" }));
+    assert_eq!(res.status, 409);
+    assert_eq!(res.json()["error"], "alias_taken");
+
+    let res = shorten(addr, json!({ "url": "https://crates.io/", "custom_alias": "crates" }));
+    assert_eq!(res.status, 200);
+}
+
+#[test]
+fn alias_policy_is_enforced() {
+    let addr = spawn_server();
+    for alias in &["stats", "Admin", "shorten", "x", "team standup", "../etc"] {
+        let res = shorten(addr, json!({ "url": "https://example.com/", "custom_alias": alias }));
+        assert_eq!(res.status, 400, "{}", alias);
+        assert_eq!(res.json()["error"], "invalid_alias");
+    }
+    let res = shorten(addr, json!({ "url": "https://example.com/standup", "custom_alias": "team-standup" }));
+    assert_eq!(res.status, 201);
+    assert_eq!(res.json()["short_url"], "https://u.rl/team-standup");
+}
+
+#[test]
+fn malformed_body_is_bad_request() {
+    let addr = spawn_server();
+    let res = request(addr, "POST", "/shorten", "https://not-json.example/");
+    assert_eq!(res.status, 400);
+    assert_eq!(res.json()["error"], "bad_request");
+
+    let res = shorten(addr, json!({ "link": "https://example.com/" }));
+    assert_eq!(res.status, 400);
+}
+
+#[test]
+fn urls_are_validated_and_normalized() {
+    let addr = spawn_server();
+    let res = shorten(addr, json!({ "url": "HTTPS://Example.NET:443/docs" }));
+    assert_eq!(res.status, 201);
+    let code = res.json()["code"].as_str().unwrap().to_string();
+    let res = request(addr, "GET", &format!("/{}", code), "");
+    assert_eq!(res.header("location"), Some("https://example.net/docs"));
+
+    for bad in &["", "not a url", "ftp://example.net/", "javascript:alert(1)"] {
+        let res = shorten(addr, json!({ "url": bad }));
+        assert_eq!(res.status, 400, "{}", bad);
+        assert_eq!(res.json()["error"], "invalid_url");
+    }
+
+    let res = request(addr, "POST", "/shorten", b"{\"url\": \"\xff\"}");
+    assert_eq!(res.status, 400);
+}
+
+#[test]
+fn expired_links_are_gone() {
+    let addr = spawn_server();
+    let soon = chrono::Utc::now() + chrono::Duration::milliseconds(300);
+    let res = shorten(addr, json!({ "url": "https://example.com/flash-sale", "expires_at": soon }));
+    assert_eq!(res.status, 201);
+    let body = res.json();
+    assert!(body["expires_at"].is_string());
+    let path = format!("/{}", body["code"].as_str().unwrap());
+
+    assert_eq!(request(addr, "GET", &path, "").status, 302);
+    thread::sleep(Duration::from_millis(400));
+    let res = request(addr, "GET", &path, "");
+    assert_eq!(res.status, 410);
+    assert_eq!(res.json()["error"], "expired");
+}
+
+#[test]
+fn expiry_must_be_sane() {
+    let addr = spawn_server();
+    let res = shorten(addr, json!({ "url": "https://example.com/", "ttl": 60 }));
+    assert_eq!(res.status, 201);
+    assert!(res.json()["expires_at"].is_string());
+
+    let past = chrono::Utc::now() - chrono::Duration::seconds(1);
+    for body in &[
+        json!({ "url": "https://example.com/", "ttl": 0 }),
+        json!({ "url": "https://example.com/", "expires_at": past }),
+        json!({ "url": "https://example.com/", "ttl": 60, "expires_at": past }),
+        json!({ "url": "https://example.com/", "ttl": u64::MAX }),
+    ] {
+        assert_eq!(shorten(addr, body.clone()).status, 400, "{}", body);
+    }
+}
+
+#[test]
+fn recent_hits_are_for_the_owner() {
+    let addr = spawn_keyed_server();
+    let created = shorten_as(addr, ALICE, json!({ "url": "https://blog.rust-lang.org/" })).json();
+    let code = created["code"].as_str().unwrap();
+    assert_eq!(request(addr, "GET", &format!("/{}", code), "").status, 302);
+
+    let stats_path = format!("/{}/stats", code);
+    let mut owned = request_as(addr, ALICE, "GET", &stats_path, "").json();
+    for _ in 0..100 {
+        if owned["total_clicks"] == 1 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+        owned = request_as(addr, ALICE, "GET", &stats_path, "").json();
+    }
+    assert_eq!(owned["recent"].as_array().unwrap().len(), 1);
+    let admin = request_as(addr, ADMIN, "GET", &stats_path, "").json();
+    assert_eq!(admin["recent"].as_array().unwrap().len(), 1);
+
+    let others = [request_as(addr, BOB, "GET", &stats_path, ""), request(addr, "GET", &stats_path, "")];
+    for reply in others {
+        assert_eq!(reply.status, 200);
+        let stats = reply.json();
+        assert_eq!(stats["total_clicks"], 1);
+        assert!(stats.get("recent").is_none());
+    }
+    assert_eq!(request_as(addr, "not-a-key", "GET", &stats_path, "").status, 401);
+}
+
+#[test]
+fn redirects_are_counted() {
+    let addr = spawn_server();
+    let code = shorten(addr, json!({ "url": "https://blog.rust-lang.org/" })).json()["code"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let path = format!("/{}", code);
+
+    for referrer in &["https://news.example/", "https://news.example/", "https://chat.example/"] {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: {}\r\nReferer: {}\r\nUser-Agent: routes-test\r\nConnection: close\r\n\r\n",
+            path, addr, referrer
+        )
+        .unwrap();
+        assert_eq!(read_reply(stream).status, 302);
+    }
+
+    let stats_path = format!("/{}/stats", code);
+    let mut stats = request(addr, "GET", &stats_path, "").json();
+    for _ in 0..100 {
+        if stats["total_clicks"] == 3 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+        stats = request(addr, "GET", &stats_path, "").json();
+    }
+    assert_eq!(stats["code"], code.as_str());
+    assert_eq!(stats["total_clicks"], 3);
+    assert_eq!(stats["daily"][0]["clicks"], 3);
+    assert_eq!(stats["top_referrers"][0], json!({ "referrer": "https://news.example/", "clicks": 2 }));
+    assert!(stats.get("recent").is_none());
+
+    assert_eq!(request(addr, "GET", "/nope0/stats", "").status, 404);
+    assert_eq!(request(addr, "POST", &stats_path, "").status, 405);
+    assert_eq!(request(addr, "GET", &format!("/{}/other", code), "").status, 404);
+}
+
+#[test]
+fn unknown_code_is_not_found() {
+    let addr = spawn_server();
+    let res = request(addr, "GET", "/nope0", "");
+    assert_eq!(res.status, 404);
+    assert_eq!(res.json()["error"], "not_found");
+    assert_eq!(request(addr, "GET", "/", "").status, 404);
+    assert_eq!(request(addr, "GET", "/a/b", "").status, 404);
+}
+
+#[test]
+fn wrong_method_is_not_allowed() {
+    let addr = spawn_server();
+    let res = request(addr, "GET", "/shorten", "");
+    assert_eq!(res.status, 405);
+    assert_eq!(res.header("allow"), Some("POST"));
+
+    let res = request(addr, "DELETE", "/abcde", "");
+    assert_eq!(res.status, 405);
+    assert_eq!(res.header("allow"), Some("GET"));
+}
+
+#[test]
+fn shutdown_drains_in_flight_requests() {
+    let server = Server::start();
+    let addr = server.addr;
+    let body = json!({ "url": "https://tokio.rs/" }).to_string();
+    let (head, tail) = body.split_at(10);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "POST /shorten HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n\r\n{}",
+        addr,
+        body.len(),
+        head
+    )
+    .unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    let stopping = thread::spawn(move || server.shutdown());
+    thread::sleep(Duration::from_millis(100));
+    assert!(TcpStream::connect(addr).is_err());
+
+    stream.write_all(tail.as_bytes()).unwrap();
+    let res = read_reply(stream);
+    assert_eq!(res.status, 201);
+    assert_eq!(res.json()["short_url"].as_str().map(|u| u.starts_with("https://u.rl/")), Some(true));
+    stopping.join().unwrap();
+}
+
+#[test]
+fn api_keys_are_required_once_configured() {
+    let addr = spawn_keyed_server();
+    let anonymous = shorten(addr, json!({ "url": "https://example.com/" }));
+    assert_eq!(anonymous.status, 401);
+    assert_eq!(anonymous.header("www-authenticate"), Some("Bearer"));
+    assert_eq!(anonymous.json()["error"], "unauthorized");
+    assert_eq!(shorten_as(addr, "not-a-key", json!({ "url": "https://example.com/" })).status, 401);
+
+    let alice = shorten_as(addr, ALICE, json!({ "url": "https://example.com/" }));
+    assert_eq!(alice.status, 201);
+    assert_eq!(shorten_as(addr, ALICE, json!({ "url": "https://example.com/" })).json()["code"], alice.json()["code"]);
+
+    // Each owner gets a link of their own for the same target.
+    let bob = shorten_as(addr, BOB, json!({ "url": "https://example.com/" }));
+    assert_eq!(bob.status, 201);
+    assert_ne!(bob.json()["code"], alice.json()["code"]);
+
+    // Redirects stay public.
+    let code = alice.json()["code"].as_str().unwrap().to_string();
+    assert_eq!(request(addr, "GET", &format!("/{}", code), "").status, 302);
+}
+
+#[test]
+fn list_links_page_by_page() {
+    let addr = spawn_keyed_server();
+    for alias in ["list-a", "list-b", "list-c"] {
+        let url = format!("https://example.com/{}", alias);
+        assert_eq!(shorten_as(addr, ALICE, json!({ "url": url, "custom_alias": alias })).status, 201);
+    }
+    shorten_as(addr, BOB, json!({ "url": "https://example.com/bob", "custom_alias": "list-bob" }));
+
+    assert_eq!(request(addr, "GET", "/api/links", "").status, 401);
+    let first = request_as(addr, ALICE, "GET", "/api/links?prefix=list-&limit=2", "").json();
+    assert_eq!(codes(&first), ["list-a", "list-b"]);
+    assert_eq!(first["links"][0]["url"], "https://example.com/list-a");
+    assert_eq!(first["links"][0]["owner"], "alice");
+    let cursor = first["next_cursor"].as_str().unwrap();
+
+    let rest = request_as(addr, ALICE, "GET", &format!("/api/links?prefix=list-&limit=2&cursor={}", cursor), "");
+    assert_eq!(rest.status, 200);
+    assert_eq!(codes(&rest.json()), ["list-c"]);
+    assert!(rest.json().get("next_cursor").is_none());
+
+    // Owners see their own links; admins see everyone's and may filter.
+    let refused = request_as(addr, ALICE, "GET", "/api/links?owner=bob", "");
+    assert_eq!(refused.status, 403);
+    assert_eq!(refused.json()["message"], "only an admin may list another owner's links");
+    let all = request_as(addr, ADMIN, "GET", "/api/links?prefix=list-", "").json();
+    assert_eq!(codes(&all), ["list-a", "list-b", "list-bob", "list-c"]);
+    let bobs = request_as(addr, ADMIN, "GET", "/api/links?owner=bob", "").json();
+    assert_eq!(codes(&bobs), ["list-bob"]);
+
+    assert_eq!(request_as(addr, ALICE, "GET", "/api/links?limit=0", "").status, 400);
+    assert_eq!(request_as(addr, ALICE, "POST", "/api/links", "").status, 405);
+}
+
+#[test]
+fn update_link_target_and_expiry() {
+    let addr = spawn_keyed_server();
+    shorten_as(addr, ALICE, json!({ "url": "https://example.com/old", "custom_alias": "patch-me", "ttl": 60 }));
+
+    let patch = |key, body: Value| request_as(addr, key, "PATCH", "/api/links/patch-me", body.to_string());
+    let refused = patch(BOB, json!({ "url": "https://evil.example/" }));
+    assert_eq!(refused.status, 403);
+    assert_eq!(refused.json()["message"], "only the owner or an admin may change this link");
+    assert_eq!(patch(ALICE, json!({})).status, 400);
+    assert_eq!(patch(ALICE, json!({ "url": "ftp://example.com/" })).status, 400);
+    assert_eq!(patch(ALICE, json!({ "ttl": 60, "expires_at": null })).status, 400);
+
+    let updated = patch(ALICE, json!({ "url": "https://example.com/new" }));
+    assert_eq!(updated.status, 200);
+    assert_eq!(updated.json()["url"], "https://example.com/new");
+    assert!(updated.json()["expires_at"].is_string());
+
+    let permanent = patch(ADMIN, json!({ "expires_at": null }));
+    assert_eq!(permanent.status, 200);
+    assert!(permanent.json().get("expires_at").is_none());
+    assert_eq!(permanent.json()["owner"], "alice");
+
+    let res = request(addr, "GET", "/patch-me", "");
+    assert_eq!(res.header("location"), Some("https://example.com/new"));
+    assert_eq!(request_as(addr, ALICE, "PATCH", "/api/links/missing", r#"{"ttl":60}"#).status, 404);
+}
+
+#[test]
+fn delete_link() {
+    let addr = spawn_keyed_server();
+    shorten_as(addr, ALICE, json!({ "url": "https://example.com/", "custom_alias": "delete-me" }));
+    request(addr, "GET", "/delete-me", "");
+
+    assert_eq!(request(addr, "DELETE", "/api/links/delete-me", "").status, 401);
+    assert_eq!(request_as(addr, BOB, "DELETE", "/api/links/delete-me", "").status, 403);
+    let shown = request_as(addr, ALICE, "GET", "/api/links/delete-me", "");
+    assert_eq!(shown.status, 200);
+    assert_eq!(shown.json()["short_url"], "https://u.rl/delete-me");
+
+    let deleted = request_as(addr, ALICE, "DELETE", "/api/links/delete-me", "");
+    assert_eq!(deleted.status, 204);
+    assert_eq!(deleted.body, "");
+    assert_eq!(request_as(addr, ALICE, "DELETE", "/api/links/delete-me", "").status, 404);
+    assert_eq!(request(addr, "GET", "/delete-me", "").status, 404);
+    assert_eq!(request(addr, "GET", "/delete-me/stats", "").status, 404);
+
+    let res = request_as(addr, ALICE, "PUT", "/api/links/delete-me", "");
+    assert_eq!(res.status, 405);
+    assert_eq!(res.header("allow"), Some("GET, PATCH, DELETE"));
+}
+
+#[test]
+fn clients_are_rate_limited() {
+    let addr = spawn_with(Config {
+        api_keys: vec![ApiKey { key: ALICE.into(), owner: "alice".into(), admin: false }],
+        create_rate_limit: "2/m".parse().unwrap(),
+        redirect_rate_limit: "3/h".parse().unwrap(),
+        ..Config::default()
+    });
+    let code = shorten_as(addr, ALICE, json!({ "url": "https://example.com/0" })).json()["code"].as_str().unwrap().to_string();
+    assert_eq!(shorten_as(addr, ALICE, json!({ "url": "https://example.com/1" })).status, 201);
+
+    let limited = shorten_as(addr, ALICE, json!({ "url": "https://example.com/2" }));
+    assert_eq!(limited.status, 429);
+    assert_eq!(limited.json()["error"], "rate_limited");
+    assert_eq!(limited.header("retry-after"), Some("30"));
+    // Authentication comes first, so bad keys never touch a budget.
+    assert_eq!(shorten(addr, json!({ "url": "https://example.com/3" })).status, 401);
+
+    for _ in 0..3 {
+        assert_eq!(request(addr, "GET", &format!("/{}", code), "").status, 302);
+    }
+    let limited = request(addr, "GET", &format!("/{}", code), "");
+    assert_eq!(limited.status, 429);
+    assert_eq!(limited.header("retry-after"), Some("1200"));
+}
+
+#[test]
+fn metrics_count_requests() {
+    let addr = spawn_server();
+    let code = shorten(addr, json!({ "url": "https://example.com/metrics" })).json()["code"].as_str().unwrap().to_string();
+    request(addr, "GET", &format!("/{}", code), "");
+    request(addr, "GET", "/nope", "");
+
+    let res = request(addr, "GET", "/metrics", "");
+    assert_eq!(res.status, 200);
+    assert!(res.header("content-type").unwrap().starts_with("text/plain"));
+    for line in [
+        "hyperurl_requests_total{route=\"shorten\",status=\"201\"} 1",
+        "hyperurl_requests_total{route=\"redirect\",status=\"302\"} 1",
+        "hyperurl_requests_total{route=\"redirect\",status=\"404\"} 1",
+        "hyperurl_request_duration_seconds_count{route=\"shorten\"} 1",
+        "hyperurl_links 1",
+        "hyperurl_redirects_total 1",
+        "hyperurl_code_collisions_total 0",
+        "hyperurl_rate_limit_allowed_total{class=\"create\"} 1",
+    ] {
+        assert!(res.body.lines().any(|l| l == line), "missing `{}` in\n{}", line, res.body);
+    }
+    assert_eq!(request(addr, "POST", "/metrics", "").status, 405);
+}
+
+#[test]
+fn bulk_shortening_reports_each_item() {
+    let addr = spawn_server();
+    let existing = shorten(addr, json!({ "url": "https://example.com/known" })).json();
+    let body = json!([
+        "https://example.com/one",
+        { "url": "https://example.com/two", "custom_alias": "bulk-two" },
+        "ftp://example.com/",
+        { "url": "https://example.com/known" },
+        { "href": "https://example.com/" },
+    ]);
+    let res = request(addr, "POST", "/api/bulk", body.to_string());
+    assert_eq!(res.status, 200);
+    let res = res.json();
+    assert_eq!((res["created"].as_u64(), res["existing"].as_u64(), res["failed"].as_u64()), (Some(2), Some(1), Some(2)));
+
+    let results = res["results"].as_array().unwrap();
+    assert_eq!(results.len(), 5);
+    assert_eq!(results[0]["index"], 0);
+    assert_eq!(results[0]["url"], "https://example.com/one");
+    assert_eq!(results[0]["created"], true);
+    assert_eq!(results[1]["short_url"], "https://u.rl/bulk-two");
+    assert_eq!(results[2]["error"], "invalid_url");
+    assert_eq!(results[3]["code"], existing["code"]);
+    assert_eq!(results[3]["created"], false);
+    assert_eq!(results[4]["error"], "bad_request");
+
+    let lines = request(addr, "POST", "/api/bulk", "https://example.com/a\nhttps://example.com/b\n").json();
+    assert_eq!(lines["created"], 2);
+    assert_eq!(request(addr, "POST", "/api/bulk", "[").status, 400);
+    assert_eq!(request(addr, "GET", "/api/bulk", "").status, 405);
+}
+
+#[test]
+fn bulk_items_have_their_own_budget() {
+    // On defaults a migration of thousands of links goes through in one go.
+    let addr = spawn_with(Config { create_rate_limit: "1/h".parse().unwrap(), ..Config::default() });
+    for batch in 0..2 {
+        let urls: Vec<String> = (0..1000).map(|n| format!("https://example.com/{}/{}", batch, n)).collect();
+        let res = request(addr, "POST", "/api/bulk", json!(urls).to_string()).json();
+        assert_eq!(res["created"], 1000);
+    }
+    assert_eq!(shorten(addr, json!({ "url": "https://example.com/solo" })).status, 201);
+
+    let addr = spawn_with(Config { bulk_rate_limit: "2/h".parse().unwrap(), ..Config::default() });
+    let body = json!(["https://example.com/1", "https://example.com/2", "https://example.com/3"]);
+    let res = request(addr, "POST", "/api/bulk", body.to_string()).json();
+    assert_eq!(res["created"], 2);
+    assert_eq!(res["results"][2]["error"], "rate_limited");
+    assert_eq!(res["results"][2]["retry_after"], 1800);
+    assert!(res["results"][1].get("retry_after").is_none());
+}
+
+#[test]
+fn oversized_bodies_are_refused() {
+    let addr = spawn_with(Config { max_body_size: 64, ..Config::default() });
+    let res = request(addr, "POST", "/shorten", vec![b' '; 65]);
+    assert_eq!(res.status, 413);
+    assert_eq!(res.json()["error"], "payload_too_large");
+
+    // Without a length up front the limit applies while streaming.
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "POST /shorten HTTP/1.1\r\nHost: {}\r\nTransfer-Encoding: chunked\r\n\r\n", addr).unwrap();
+    for _ in 0..4 {
+        stream.write_all(b"20\r\n                                \r\n").unwrap();
+    }
+    let res = read_reply(stream);
+    assert_eq!(res.status, 413);
+    assert_eq!(res.header("connection"), Some("close"));
+
+    let url = json!({ "url": "https://example.com/" }).to_string();
+    assert!(url.len() <= 64);
+    assert_eq!(request(addr, "POST", "/shorten", url).status, 201);
+}
+
+#[test]
+fn slow_clients_time_out() {
+    let addr = spawn_with(Config { header_timeout: 1, body_timeout: 1, ..Config::default() });
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    write!(stream, "POST /shorten HTTP/1.1\r\nHost: {}\r\nContent-Length: 100\r\n\r\n{{\"url\":", addr).unwrap();
+    let res = read_reply(stream);
+    assert_eq!(res.status, 408);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    write!(stream, "GET / HTTP/1.1\r\nHost: ").unwrap();
+    let mut rest = Vec::new();
+    // Closed by the server rather than left hanging.
+    stream.read_to_end(&mut rest).unwrap();
+}
+
+#[cfg(feature = "qr")]
+#[test]
+fn qr_codes_for_short_links() {
+    let addr = spawn_server();
+    shorten(addr, json!({ "url": "https://example.com/print", "custom_alias": "print" }));
+
+    let svg = request(addr, "GET", "/print/qr", "");
+    assert_eq!(svg.status, 200);
+    assert_eq!(svg.header("content-type"), Some("image/svg+xml"));
+    assert!(svg.body.contains("<svg"));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "GET /print/qr?format=png&size=120&ecc=H HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", addr).unwrap();
+    let mut raw = Vec::new();
+    stream.read_to_end(&mut raw).unwrap();
+    let head = String::from_utf8_lossy(&raw[..raw.windows(4).position(|w| w == b"\r\n\r\n").unwrap()]).to_string();
+    assert!(head.starts_with("HTTP/1.1 200"));
+    assert!(head.to_ascii_lowercase().contains("content-type: image/png"));
+    assert!(raw.windows(8).any(|w| w == b"\x89PNG\r\n\x1a\n"));
+
+    assert_eq!(request(addr, "GET", "/print/qr?size=0", "").status, 400);
+    assert_eq!(request(addr, "GET", "/missing/qr", "").status, 404);
+    assert_eq!(request(addr, "POST", "/print/qr", "").status, 405);
+}
+
+#[test]
+fn health_readiness_and_version() {
+    let addr = spawn_server();
+    let health = request(addr, "GET", "/healthz", "");
+    assert_eq!(health.status, 200);
+    assert_eq!(health.json()["status"], "ok");
+    assert_eq!(request(addr, "GET", "/readyz", "").json()["status"], "ready");
+
+    let version = request(addr, "GET", "/version", "").json();
+    assert_eq!(version["version"], env!("CARGO_PKG_VERSION"));
+    assert!(version["features"].is_array());
+    assert_eq!(request(addr, "POST", "/healthz", "").status, 405);
+}
+
+#[test]
+fn readiness_follows_the_store() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Arc::new(FileStore::open(dir.path().join("links.db")).unwrap());
+    let server = Server::with_store(Config::default(), db);
+    assert_eq!(request(server.addr, "GET", "/readyz", "").status, 200);
+
+    drop(dir);
+    let res = request(server.addr, "GET", "/readyz", "");
+    assert_eq!(res.status, 503);
+    assert_eq!(res.json()["error"], "not_ready");
+    server.shutdown();
+}
+
+#[test]
+fn admin_maintenance() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Arc::new(FileStore::open(dir.path().join("links.db")).unwrap());
+    let keys = vec![
+        ApiKey { key: ALICE.into(), owner: "alice".into(), admin: false },
+        ApiKey { key: ADMIN.into(), owner: "ops".into(), admin: true },
+    ];
+    let server = Server::with_store(Config { api_keys: keys.clone(), ..Config::default() }, db);
+    let addr = server.addr;
+    shorten_as(addr, ALICE, json!({ "url": "https://example.com/" }));
+
+    assert_eq!(request(addr, "POST", "/admin/compact", "").status, 401);
+    let refused = request_as(addr, ALICE, "POST", "/admin/compact", "");
+    assert_eq!(refused.status, 403);
+    assert_eq!(refused.json()["message"], "admin key required");
+    assert_eq!(request_as(addr, ADMIN, "GET", "/admin/compact", "").status, 405);
+
+    let compacted = request_as(addr, ADMIN, "POST", "/admin/compact", "");
+    assert_eq!(compacted.status, 200);
+    assert_eq!(std::fs::metadata(dir.path().join("links.db.wal")).unwrap().len(), 0);
+
+    let snapshot = request_as(addr, ADMIN, "POST", "/admin/snapshot", "").json();
+    let copy = FileStore::open(snapshot["snapshot"].as_str().unwrap()).unwrap();
+    assert_eq!(copy.len(), 1);
+    server.shutdown();
+
+    // The memory store has nothing to compact.
+    let addr = spawn_with(Config { api_keys: keys, ..Config::default() });
+    let res = request_as(addr, ADMIN, "POST", "/admin/snapshot", "");
+    assert_eq!(res.status, 501);
+    assert_eq!(res.json()["error"], "unsupported");
+}
+
+#[test]
+fn import_keeps_codes_and_metadata() {
+    let addr = spawn_keyed_server();
+    let jsonl = "{\"code\":\"1\",\"url\":\"https://example.com/a\",\"created_at\":\"2020-01-01T00:00:00Z\",\"owner\":\"bob\"}\n\
+                 not json\n\
+                 {\"code\":\"old\",\"url\":\"https://example.com/b\",\"expires_at\":\"2020-01-02T00:00:00Z\"}\n";
+    assert_eq!(request(addr, "POST", "/api/import", jsonl).status, 401);
+    let refused = request_as(addr, ALICE, "POST", "/api/import", jsonl);
+    assert_eq!(refused.status, 403);
+    assert_eq!(refused.json()["message"], "admin key required");
+    assert_eq!(request_as(addr, ADMIN, "GET", "/api/import", "").status, 405);
+
+    let res = request_as(addr, ADMIN, "POST", "/api/import", jsonl);
+    assert_eq!(res.status, 200);
+    let report = res.json();
+    assert_eq!(report["imported"], 2);
+    assert_eq!(report["invalid"][0]["line"], 2);
+    let link = request_as(addr, ADMIN, "GET", "/api/links/1", "").json();
+    assert_eq!(link["owner"], "bob");
+    assert_eq!(link["created_at"], "2020-01-01T00:00:00Z");
+    assert_eq!(request(addr, "GET", "/old", "").status, 410);
+
+    let csv = "code,url,created_at,expires_at,owner\n1,https://example.com/other,2020-01-01T00:00:00Z,,\n";
+    let res = request_with(
+        addr,
+        "POST",
+        "/api/import",
+        &[("Authorization", &format!("Bearer {}", ADMIN)), ("Content-Type", "text/csv")],
+        csv,
+    );
+    assert_eq!(res.json()["conflicts"][0], json!({ "line": 2, "message": "code `1` already holds another link" }));
+}
+
+#[cfg(feature = "tls")]
+mod https {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+    use std::time::Instant;
+    use tokio_rustls::rustls::crypto::ring;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+    use tokio_rustls::rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+    /// Writes a fresh self-signed pair for localhost and returns the
+    /// certificate for clients to trust.
+    fn self_signed(dir: &Path) -> CertificateDer<'static> {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        fs::write(dir.join("cert.pem"), generated.cert.pem()).unwrap();
+        fs::write(dir.join("key.pem"), generated.signing_key.serialize_pem()).unwrap();
+        generated.cert.der().clone()
+    }
+
+    fn get(addr: SocketAddr, trusted: &CertificateDer<'static>, path: &str) -> std::io::Result<Reply> {
+        let mut roots = RootCertStore::empty();
+        roots.add(trusted.clone()).unwrap();
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let conn = ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap()).unwrap();
+        let mut stream = StreamOwned::new(conn, TcpStream::connect(addr)?);
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path)?;
+        stream.flush()?;
+        Ok(read_reply(stream))
+    }
+
+    #[test]
+    fn serves_https_and_reloads_certificates() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+        let first = self_signed(dir.path());
+        let certs = Arc::new(Certificates::load(&cert, &key).unwrap());
+        let server = Server::with_tls(Config::default(), certs.acceptor());
+
+        let res = get(server.addr, &first, "/healthz").unwrap();
+        assert_eq!(res.status, 200);
+        assert_eq!(res.json()["status"], "ok");
+
+        let second = self_signed(dir.path());
+        certs.reload().unwrap();
+        assert!(get(server.addr, &first, "/healthz").is_err());
+        assert_eq!(get(server.addr, &second, "/healthz").unwrap().status, 200);
+
+        // Plain HTTP on the TLS port gets no HTTP answer.
+        let mut plain = TcpStream::connect(server.addr).unwrap();
+        write!(plain, "GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut raw = Vec::new();
+        let _ = plain.read_to_end(&mut raw);
+        assert!(!raw.starts_with(b"HTTP/"));
+        server.shutdown();
+    }
+
+    #[test]
+    fn shutdown_drops_unfinished_handshakes() {
+        let dir = tempfile::tempdir().unwrap();
+        self_signed(dir.path());
+        let (cert, key) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+        let certs = Arc::new(Certificates::load(&cert, &key).unwrap());
+        let config = Config { header_timeout: 60, ..Config::default() };
+        let server = Server::with_tls(config, certs.acceptor());
+
+        // Connected, but never starts the handshake.
+        let mut stalled = TcpStream::connect(server.addr).unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        let started = Instant::now();
+        server.shutdown();
+        let took = started.elapsed();
+        assert!(took < Duration::from_secs(5), "shutdown took {:?}", took);
+        stalled.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(stalled.read(&mut [0; 16]).unwrap(), 0);
+    }
+}
@@ -0,0 +1,468 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, info, trace};
+use reqwest::blocking::{RequestBuilder, Response};
+use reqwest::header::{CONTENT_TYPE, LOCATION, RETRY_AFTER};
+use reqwest::redirect::Policy;
+use reqwest::{Method, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{CliError, Result};
+
+/// Links fetched per request by `list` and `export`.
+const PAGE: usize = 500;
+
+/// URLs sent per bulk request; well under the server's limit of 1000,
+/// and small enough to stay below its default body size limit.
+const BULK_CHUNK: usize = 200;
+
+/// Longest `Retry-After` a batch waits out before sending the links the
+/// rate limit refused again; those that wait longer fail.
+const MAX_RETRY_WAIT: Duration = Duration::from_secs(60);
+
+/// Times a chunk's rate limited links are sent again.
+const RETRIES: usize = 3;
+
+/// Where the links live: a hyperurl server, or a store file with `--local`.
+pub trait Links {
+    fn create(&self, link: &NewLink) -> Result<Created>;
+
+    /// Shortens every link in `links`, handing each result to `each` in
+    /// input order. Items fail one by one; only a failure of the whole
+    /// batch, such as a lost connection, stops it.
+    fn create_many(
+        &self,
+        links: &[NewLink],
+        _jobs: usize,
+        each: &mut dyn FnMut(usize, Result<Created>) -> Result<()>,
+    ) -> Result<()> {
+        for (i, link) in links.iter().enumerate() {
+            each(i, self.create(link))?;
+        }
+        Ok(())
+    }
+
+    /// The URL `code` redirects to.
+    fn resolve(&self, code: &str) -> Result<String>;
+
+    /// The short link `resolve` follows for `code`.
+    fn short_url(&self, code: &str) -> String;
+
+    /// Calls `each` for every matching link and returns how many there were.
+    fn list(&self, filter: &ListFilter, each: &mut dyn FnMut(LinkInfo) -> Result<()>) -> Result<usize>;
+
+    fn delete(&self, code: &str) -> Result<()>;
+
+    fn stats(&self, code: &str) -> Result<Stats>;
+
+    /// Stores exported links under their own codes, owners and dates,
+    /// handing each result to `each` in input order. A code already
+    /// holding the very same link counts as imported.
+    fn import(&self, links: &[LinkInfo], each: &mut dyn FnMut(usize, Result<()>) -> Result<()>) -> Result<()>;
+}
+
+/// Blocking client for one hyperurl server.
+pub struct Client {
+    http: reqwest::blocking::Client,
+    server: String,
+    api_key: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct NewLink {
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub custom_alias: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<String>,
+}
+
+/// Output records keep every field, null or empty when unset, so JSON keys
+/// and CSV columns stay the same from row to row.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Created {
+    pub code: String,
+    pub short_url: String,
+    /// The URL as stored, after the server normalized it. Older servers
+    /// leave it out, and the URL as sent stands in.
+    #[serde(default)]
+    pub url: String,
+    pub created_at: String,
+    #[serde(default)]
+    pub expires_at: Option<String>,
+}
+
+/// One link as listed by the server, and one line of `export`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkInfo {
+    pub code: String,
+    /// Missing from files written by `hyperurl export`.
+    #[serde(default)]
+    pub short_url: String,
+    pub url: String,
+    pub created_at: String,
+    #[serde(default)]
+    pub expires_at: Option<String>,
+    #[serde(default)]
+    pub owner: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LinkPage {
+    links: Vec<LinkInfo>,
+    #[serde(default)]
+    next_cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Stats {
+    pub code: String,
+    pub total_clicks: u64,
+    pub daily: Vec<DayCount>,
+    pub top_referrers: Vec<ReferrerCount>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DayCount {
+    pub date: String,
+    pub clicks: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReferrerCount {
+    pub referrer: String,
+    pub clicks: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorBody {
+    error: String,
+    message: String,
+    /// Seconds to wait, sent with rate limited bulk items.
+    #[serde(default)]
+    retry_after: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BulkResponse {
+    results: Vec<BulkResult>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BulkResult {
+    index: usize,
+    #[serde(flatten)]
+    outcome: BulkOutcome,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum BulkOutcome {
+    Shortened(Created),
+    Failed(ErrorBody),
+}
+
+#[derive(Debug, Deserialize)]
+struct ImportResponse {
+    conflicts: Vec<ImportProblem>,
+    invalid: Vec<ImportProblem>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImportProblem {
+    line: usize,
+    message: String,
+}
+
+/// Narrows a `list`; every field is optional.
+#[derive(Debug, Default)]
+pub struct ListFilter {
+    pub owner: Option<String>,
+    pub prefix: Option<String>,
+    pub limit: Option<usize>,
+}
+
+impl Client {
+    /// `server` may omit the scheme, in which case plain HTTP is assumed.
+    pub fn new(server: &str, api_key: Option<String>) -> Result<Self> {
+        let http = reqwest::blocking::Client::builder()
+            .user_agent(concat!("shorten/", env!("CARGO_PKG_VERSION")))
+            // `resolve` wants the redirect itself, not its target.
+            .redirect(Policy::none())
+            .build()?;
+        Ok(Client { http, server: server_url(server)?, api_key })
+    }
+
+    fn bulk(&self, links: &[NewLink]) -> Result<Vec<Result<Created>>> {
+        let res = self.send(self.request(Method::POST, &["api", "bulk"]).json(links))?;
+        let answer: BulkResponse = res.json()?;
+        let mut results: Vec<Option<Result<Created>>> = links.iter().map(|_| None).collect();
+        for item in answer.results {
+            let result = match item.outcome {
+                BulkOutcome::Shortened(created) => Ok(created),
+                BulkOutcome::Failed(ErrorBody { retry_after: Some(secs), message, .. }) => {
+                    Err(CliError::RateLimited { status: None, message, retry_after: Some(Duration::from_secs(secs)) })
+                }
+                BulkOutcome::Failed(body) => Err(CliError::from_kind(body.error, body.message)),
+            };
+            if let Some(slot) = results.get_mut(item.index) {
+                *slot = Some(result);
+            }
+        }
+        Ok(results
+            .into_iter()
+            .map(|result| {
+                result.unwrap_or_else(|| {
+                    Err(CliError::Server {
+                        status: None,
+                        kind: "bad_response".to_string(),
+                        message: format!("{} left an item of the batch unanswered", self.server),
+                    })
+                })
+            })
+            .collect())
+    }
+
+    /// Shortens a chunk through the bulk endpoint, or one link at a time
+    /// once `bulk` is cleared, which happens on servers without one.
+    fn create_chunk(&self, links: &[NewLink], jobs: usize, bulk: &mut bool) -> Result<Vec<Result<Created>>> {
+        if *bulk {
+            match self.bulk(links) {
+                Err(CliError::Rejected { status: Some(404 | 405), .. }) => {
+                    info!("{} has no bulk endpoint, sending links one at a time", self.server);
+                    *bulk = false;
+                }
+                results => return results,
+            }
+        }
+        Ok(self.create_each(links, jobs))
+    }
+
+    /// Sends `links` as JSON lines, so the server's line numbers point at them.
+    fn import_chunk(&self, links: &[LinkInfo]) -> Result<Vec<Result<()>>> {
+        let mut body = Vec::new();
+        for link in links {
+            serde_json::to_writer(&mut body, link)?;
+            body.push(b'\n');
+        }
+        let req = self.request(Method::POST, &["api", "import"]).header(CONTENT_TYPE, "application/x-ndjson");
+        let res = match self.send(req.body(body)) {
+            Err(CliError::Rejected { status: Some(status @ (404 | 405)), kind, .. }) => {
+                let message = format!("{} cannot import links, it predates `POST /api/import`", self.server);
+                return Err(CliError::Rejected { status: Some(status), kind, message });
+            }
+            res => res?,
+        };
+        let answer: ImportResponse = res.json()?;
+        let conflicts = answer.conflicts.into_iter().map(|p| (p.line, "alias_taken", p.message));
+        let invalid = answer.invalid.into_iter().map(|p| (p.line, "invalid_link", p.message));
+        Ok(import_results(links.len(), conflicts.chain(invalid)))
+    }
+
+    /// One request per link, `jobs` at a time.
+    fn create_each(&self, links: &[NewLink], jobs: usize) -> Vec<Result<Created>> {
+        let next = AtomicUsize::new(0);
+        let results = Mutex::new(links.iter().map(|_| None).collect::<Vec<_>>());
+        thread::scope(|scope| {
+            for _ in 0..jobs.clamp(1, links.len().max(1)) {
+                scope.spawn(|| loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(link) = links.get(i) else { break };
+                    let result = self.create(link);
+                    results.lock().unwrap()[i] = Some(result);
+                });
+            }
+        });
+        results.into_inner().unwrap().into_iter().map(|r| r.expect("every link was sent")).collect()
+    }
+
+    /// Appends `path` to the server URL a segment at a time, so a code
+    /// holding `/`, `?`, `#` or `%` stays one segment.
+    fn url(&self, path: &[&str]) -> Url {
+        let mut url = Url::parse(&self.server).expect("server_url checked it");
+        url.path_segments_mut().expect("http URLs have a path").pop_if_empty().extend(path);
+        url
+    }
+
+    fn request(&self, method: Method, path: &[&str]) -> RequestBuilder {
+        let req = self.http.request(method, self.url(path));
+        match self.api_key {
+            Some(ref key) => req.bearer_auth(key),
+            None => req,
+        }
+    }
+
+    /// Sends `req` and turns error statuses into errors carrying the
+    /// server's error name and message.
+    fn send(&self, req: RequestBuilder) -> Result<Response> {
+        let req = req.build()?;
+        debug!("{} {}", req.method(), req.url());
+        let res = self.http.execute(req)?;
+        let status = res.status();
+        trace!("answered {}", status);
+        if status.is_success() || status.is_redirection() {
+            return Ok(res);
+        }
+        let retry_after = res
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_secs);
+        let (kind, message) = match res.json::<ErrorBody>() {
+            Ok(body) => (body.error, body.message),
+            // Not hyperurl, or a proxy in between.
+            Err(_) => ("http".to_string(), format!("{} answered {}", self.server, status)),
+        };
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            return Err(CliError::RateLimited { status: Some(status.as_u16()), message, retry_after });
+        }
+        Err(CliError::from_status(status.as_u16(), kind, message))
+    }
+}
+
+impl Links for Client {
+    fn create(&self, link: &NewLink) -> Result<Created> {
+        let res = self.send(self.request(Method::POST, &["shorten"]).json(link))?;
+        let mut created: Created = res.json()?;
+        if created.url.is_empty() {
+            created.url = link.url.clone();
+        }
+        Ok(created)
+    }
+
+    /// Uses the bulk endpoint, or `jobs` parallel requests on servers
+    /// without one. Links the rate limit refuses are sent again once the
+    /// server's `Retry-After` has passed, if that is soon enough.
+    fn create_many(
+        &self,
+        links: &[NewLink],
+        jobs: usize,
+        each: &mut dyn FnMut(usize, Result<Created>) -> Result<()>,
+    ) -> Result<()> {
+        let mut bulk = true;
+        for (n, chunk) in links.chunks(BULK_CHUNK).enumerate() {
+            let mut results = self.create_chunk(chunk, jobs, &mut bulk)?;
+            for _ in 0..RETRIES {
+                let limited: Vec<usize> = (0..results.len()).filter(|&i| retry_wait(&results[i]).is_some()).collect();
+                let Some(wait) = limited.iter().filter_map(|&i| retry_wait(&results[i])).max() else { break };
+                info!("rate limited, sending {} links again in {}s", limited.len(), wait.as_secs());
+                thread::sleep(wait);
+                let again: Vec<NewLink> = limited.iter().map(|&i| chunk[i].clone()).collect();
+                for (i, result) in limited.into_iter().zip(self.create_chunk(&again, jobs, &mut bulk)?) {
+                    results[i] = result;
+                }
+            }
+            for (i, result) in results.into_iter().enumerate() {
+                each(n * BULK_CHUNK + i, result)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts as a click.
+    fn resolve(&self, code: &str) -> Result<String> {
+        let res = self.send(self.request(Method::GET, &[code]))?;
+        res.headers()
+            .get(LOCATION)
+            .and_then(|url| url.to_str().ok())
+            .map(str::to_string)
+            .ok_or_else(|| CliError::Server {
+                status: Some(res.status().as_u16()),
+                kind: "no_location".to_string(),
+                message: format!("{} answered without a Location", self.server),
+            })
+    }
+
+    fn short_url(&self, code: &str) -> String {
+        self.url(&[code]).into()
+    }
+
+    /// Fetches a page at a time.
+    fn list(&self, filter: &ListFilter, each: &mut dyn FnMut(LinkInfo) -> Result<()>) -> Result<usize> {
+        let mut seen = 0;
+        let mut cursor: Option<String> = None;
+        loop {
+            let wanted = filter.limit.map_or(PAGE, |limit| PAGE.min(limit - seen));
+            if wanted == 0 {
+                return Ok(seen);
+            }
+            let mut query = vec![("limit", wanted.to_string())];
+            query.extend(cursor.take().map(|c| ("cursor", c)));
+            query.extend(filter.owner.clone().map(|o| ("owner", o)));
+            query.extend(filter.prefix.clone().map(|p| ("prefix", p)));
+
+            let res = self.send(self.request(Method::GET, &["api", "links"]).query(&query))?;
+            let page: LinkPage = res.json()?;
+            for link in page.links {
+                each(link)?;
+                seen += 1;
+            }
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => return Ok(seen),
+            }
+        }
+    }
+
+    fn delete(&self, code: &str) -> Result<()> {
+        self.send(self.request(Method::DELETE, &["api", "links", code]))?;
+        Ok(())
+    }
+
+    fn stats(&self, code: &str) -> Result<Stats> {
+        let res = self.send(self.request(Method::GET, &[code, "stats"]))?;
+        Ok(res.json()?)
+    }
+
+    /// Needs an admin key, since the links keep the owners they had.
+    fn import(&self, links: &[LinkInfo], each: &mut dyn FnMut(usize, Result<()>) -> Result<()>) -> Result<()> {
+        for (n, chunk) in links.chunks(BULK_CHUNK).enumerate() {
+            for (i, result) in self.import_chunk(chunk)?.into_iter().enumerate() {
+                each(n * BULK_CHUNK + i, result)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How long to wait before sending a rate limited link again, when the
+/// server said and it is worth waiting for.
+fn retry_wait(result: &Result<Created>) -> Option<Duration> {
+    match result {
+        Err(CliError::RateLimited { retry_after: Some(wait), .. }) if *wait <= MAX_RETRY_WAIT => Some(*wait),
+        _ => None,
+    }
+}
+
+/// Results of importing `len` links sent one per line, from the problems
+/// found on some of those lines as (line, error name, message).
+pub fn import_results<'a>(
+    len: usize,
+    problems: impl IntoIterator<Item = (usize, &'a str, String)>,
+) -> Vec<Result<()>> {
+    let mut results: Vec<Result<()>> = (0..len).map(|_| Ok(())).collect();
+    for (line, kind, message) in problems {
+        if let Some(slot) = line.checked_sub(1).and_then(|i| results.get_mut(i)) {
+            *slot = Err(CliError::from_kind(kind.to_string(), message));
+        }
+    }
+    results
+}
+
+/// `server` without a trailing slash, with `http://` added when it names
+/// no scheme.
+pub fn server_url(server: &str) -> Result<String> {
+    let server = if server.contains("://") {
+        server.trim_end_matches('/').to_string()
+    } else {
+        format!("http://{}", server.trim_end_matches('/'))
+    };
+    Url::parse(&server).map_err(|e| CliError::Invalid(format!("server `{}`: {}", server, e)))?;
+    Ok(server)
+}
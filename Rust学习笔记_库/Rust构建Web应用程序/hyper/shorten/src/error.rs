@@ -0,0 +1,157 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+/// Exit statuses; also listed in `--help`.
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_INVALID: i32 = 2;
+pub const EXIT_NETWORK: i32 = 3;
+pub const EXIT_SERVER: i32 = 4;
+pub const EXIT_RATE_LIMITED: i32 = 5;
+
+pub type Result<T, E = CliError> = std::result::Result<T, E>;
+
+#[derive(Debug)]
+pub enum CliError {
+    /// The server could not be reached, or the connection broke.
+    Network(reqwest::Error),
+    /// The server answered, but not with what hyperurl sends.
+    BadResponse(reqwest::Error),
+    /// The server refused the request (4xx). `kind` is its error name,
+    /// e.g. `alias_taken`. Items of a bulk request fail without a status.
+    Rejected { status: Option<u16>, kind: String, message: String },
+    /// The server's rate limit refused the request (429), asking to wait
+    /// `retry_after` when it said how long.
+    RateLimited { status: Option<u16>, message: String, retry_after: Option<Duration> },
+    /// The server failed (5xx) or answered something unexpected.
+    Server { status: Option<u16>, kind: String, message: String },
+    /// Bad input caught before anything was sent.
+    Invalid(String),
+    Io(io::Error),
+}
+
+impl CliError {
+    pub fn from_status(status: u16, kind: String, message: String) -> Self {
+        if (400..500).contains(&status) {
+            CliError::Rejected { status: Some(status), kind, message }
+        } else {
+            CliError::Server { status: Some(status), kind, message }
+        }
+    }
+
+    /// A failed item of a bulk request, which only says what went wrong.
+    pub fn from_kind(kind: String, message: String) -> Self {
+        match &*kind {
+            "internal" => CliError::Server { status: None, kind, message },
+            "rate_limited" => CliError::RateLimited { status: None, message, retry_after: None },
+            _ => CliError::Rejected { status: None, kind, message },
+        }
+    }
+
+    /// Stable name for scripts: the server's error name when there is one.
+    pub fn kind(&self) -> &str {
+        match self {
+            CliError::Network(_) => "network",
+            CliError::BadResponse(_) => "bad_response",
+            CliError::Rejected { kind, .. } | CliError::Server { kind, .. } => kind,
+            CliError::RateLimited { .. } => "rate_limited",
+            CliError::Invalid(_) => "invalid",
+            CliError::Io(_) => "io",
+        }
+    }
+
+    /// The reason alone; `Display` adds the HTTP status.
+    pub fn message(&self) -> String {
+        match self {
+            CliError::Rejected { message, .. }
+            | CliError::RateLimited { message, .. }
+            | CliError::Server { message, .. } => message.clone(),
+            other => other.to_string(),
+        }
+    }
+
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            CliError::Rejected { status, .. }
+            | CliError::RateLimited { status, .. }
+            | CliError::Server { status, .. } => *status,
+            _ => None,
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CliError::Network(_) => EXIT_NETWORK,
+            CliError::Rejected { .. } | CliError::Invalid(_) => EXIT_INVALID,
+            CliError::Server { .. } | CliError::BadResponse(_) => EXIT_SERVER,
+            CliError::RateLimited { .. } => EXIT_RATE_LIMITED,
+            CliError::Io(_) => EXIT_FAILURE,
+        }
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::Network(e) => write!(f, "{}", e),
+            CliError::BadResponse(e) => write!(f, "unexpected answer: {}", e),
+            CliError::Rejected { status: Some(status), message, .. }
+            | CliError::RateLimited { status: Some(status), message, .. }
+            | CliError::Server { status: Some(status), message, .. } => write!(f, "{} (HTTP {})", message, status),
+            CliError::Rejected { message, .. }
+            | CliError::RateLimited { message, .. }
+            | CliError::Server { message, .. } => write!(f, "{}", message),
+            CliError::Invalid(reason) => write!(f, "{}", reason),
+            CliError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for CliError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            // The errors themselves are shown by `Display`.
+            CliError::Network(e) | CliError::BadResponse(e) => e.source(),
+            CliError::Io(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for CliError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_decode() {
+            CliError::BadResponse(e)
+        } else {
+            CliError::Network(e)
+        }
+    }
+}
+
+impl From<io::Error> for CliError {
+    fn from(e: io::Error) -> Self {
+        CliError::Io(e)
+    }
+}
+
+/// Failures to write output are I/O errors; anything else is bad input.
+impl From<serde_json::Error> for CliError {
+    fn from(e: serde_json::Error) -> Self {
+        if e.is_io() {
+            CliError::Io(e.into())
+        } else {
+            CliError::Invalid(e.to_string())
+        }
+    }
+}
+
+impl From<csv::Error> for CliError {
+    fn from(e: csv::Error) -> Self {
+        if e.is_io_error() {
+            CliError::Io(e.into())
+        } else {
+            CliError::Invalid(e.to_string())
+        }
+    }
+}
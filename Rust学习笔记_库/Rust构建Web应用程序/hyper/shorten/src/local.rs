@@ -0,0 +1,217 @@
+use std::io;
+use std::path::Path;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use hyperurl_core::shortener::{claim_alias, expiry, shorten_url, ClaimError, CodeGenerator, CodeScheme};
+use hyperurl_core::store::{FileStore, Link, LinkFilter, UrlStore};
+use hyperurl_core::transfer::{self, Format};
+use hyperurl_core::validate::{AliasPolicy, UrlPolicy};
+use log::debug;
+
+use crate::client::{import_results, server_url, Created, LinkInfo, Links, ListFilter, NewLink, Stats};
+use crate::error::{CliError, Result};
+
+/// Links fetched per store page by `list`.
+const PAGE: usize = 500;
+
+/// Code length the server uses unless configured otherwise.
+const CODE_LENGTH: usize = 5;
+
+/// A store file worked on directly, with the server's default rules, so
+/// hyperurl can serve it later. Links are anonymous and clicks are not
+/// counted.
+pub struct Local {
+    db: FileStore,
+    codes: Box<dyn CodeGenerator>,
+    policy: UrlPolicy,
+    aliases: AliasPolicy,
+    /// Where short URLs point: the server that would serve the file.
+    base_url: String,
+}
+
+impl Local {
+    /// Opens the store at `path`, creating it when missing.
+    pub fn open(path: &Path, server: &str) -> Result<Self> {
+        let db = FileStore::open(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        debug!("opened {} with {} links", path.display(), db.len());
+        Ok(Local {
+            codes: CodeScheme::Hash.generator(CODE_LENGTH, &db),
+            db,
+            policy: UrlPolicy::default(),
+            aliases: AliasPolicy::default(),
+            base_url: server_url(server)?,
+        })
+    }
+}
+
+impl Links for Local {
+    fn create(&self, req: &NewLink) -> Result<Created> {
+        let url = self
+            .policy
+            .normalize(&req.url)
+            .map_err(|e| rejected("invalid_url", format!("invalid url: {}", e)))?;
+        let expires_at = req
+            .expires_at
+            .as_deref()
+            .map(|at| DateTime::parse_from_rfc3339(at).map_err(|e| bad_request(format!("expires_at `{}`: {}", at, e))))
+            .transpose()?;
+        let expires_at = expiry(req.ttl, expires_at.map(|at| at.with_timezone(&Utc)), Utc::now())
+            .map_err(|e| bad_request(e.to_string()))?;
+        let link = Link::new(url).expiring(expires_at);
+        let shortened = match req.custom_alias {
+            Some(ref alias) => claim_alias(&self.db, &self.aliases, alias.clone(), link).map_err(claim_failed)?,
+            None => shorten_url(&self.db, &*self.codes, &self.aliases, link)?,
+        };
+        Ok(Created {
+            short_url: self.short_url(&shortened.code),
+            code: shortened.code,
+            url: shortened.link.url,
+            created_at: timestamp(shortened.link.created_at),
+            expires_at: shortened.link.expires_at.map(timestamp),
+        })
+    }
+
+    /// Does not count as a click.
+    fn resolve(&self, code: &str) -> Result<String> {
+        let link = self.db.get(code).ok_or_else(|| rejected("not_found", "no such short link"))?;
+        if link.is_expired(Utc::now()) {
+            return Err(rejected("expired", "short link has expired"));
+        }
+        Ok(link.url)
+    }
+
+    fn short_url(&self, code: &str) -> String {
+        format!("{}/{}", self.base_url, code)
+    }
+
+    fn list(&self, filter: &ListFilter, each: &mut dyn FnMut(LinkInfo) -> Result<()>) -> Result<usize> {
+        let links = LinkFilter { owner: filter.owner.clone(), prefix: filter.prefix.clone() };
+        let mut seen = 0;
+        let mut after: Option<String> = None;
+        loop {
+            let wanted = filter.limit.map_or(PAGE, |limit| PAGE.min(limit - seen));
+            if wanted == 0 {
+                return Ok(seen);
+            }
+            let page = self.db.page(after.as_deref(), wanted, &links);
+            let last = page.len() < wanted;
+            for (code, link) in page {
+                after = Some(code.clone());
+                each(LinkInfo {
+                    short_url: self.short_url(&code),
+                    code,
+                    url: link.url,
+                    created_at: timestamp(link.created_at),
+                    expires_at: link.expires_at.map(timestamp),
+                    owner: link.owner,
+                })?;
+                seen += 1;
+            }
+            if last {
+                return Ok(seen);
+            }
+        }
+    }
+
+    fn delete(&self, code: &str) -> Result<()> {
+        match self.db.remove(code)? {
+            Some(_) => Ok(()),
+            None => Err(rejected("not_found", "no such short link")),
+        }
+    }
+
+    fn stats(&self, _code: &str) -> Result<Stats> {
+        Err(rejected("unsupported", "click statistics are only kept by a server"))
+    }
+
+    /// The same checks `hyperurl import` makes.
+    fn import(&self, links: &[LinkInfo], each: &mut dyn FnMut(usize, Result<()>) -> Result<()>) -> Result<()> {
+        let mut lines = Vec::new();
+        for link in links {
+            serde_json::to_writer(&mut lines, link)?;
+            lines.push(b'\n');
+        }
+        let report = transfer::import(&self.db, Format::Jsonl, &lines[..], &self.policy, &self.aliases, false)?;
+        let conflicts = report
+            .conflicts
+            .into_iter()
+            .map(|(line, code)| (line, "alias_taken", format!("code `{}` already holds another link", code)));
+        let invalid = report.invalid.into_iter().map(|(line, message)| (line, "invalid_link", message));
+        for (i, result) in import_results(links.len(), conflicts.chain(invalid)).into_iter().enumerate() {
+            each(i, result)?;
+        }
+        Ok(())
+    }
+}
+
+/// Formatted as the server sends it.
+fn timestamp(at: DateTime<Utc>) -> String {
+    at.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+fn rejected(kind: &str, message: impl Into<String>) -> CliError {
+    CliError::from_kind(kind.to_string(), message.into())
+}
+
+/// Named as the server names these errors.
+fn claim_failed(e: ClaimError) -> CliError {
+    match e {
+        ClaimError::Invalid(e) => rejected("invalid_alias", format!("invalid alias: {}", e)),
+        ClaimError::Taken(_) => rejected("alias_taken", e.to_string()),
+        ClaimError::Store(e) => e.into(),
+    }
+}
+
+fn bad_request(reason: String) -> CliError {
+    rejected("bad_request", format!("bad request: {}", reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_link(url: &str) -> NewLink {
+        NewLink { url: url.to_string(), ..NewLink::default() }
+    }
+
+    #[test]
+    fn links_survive_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("links.db");
+        let local = Local::open(&path, "u.rl").unwrap();
+        let created = local.create(&new_link("https://Example.com:443/a")).unwrap();
+        assert_eq!(created.url, "https://example.com/a");
+        assert_eq!(created.short_url, format!("http://u.rl/{}", created.code));
+        let alias = NewLink { custom_alias: Some("docs".into()), ttl: Some(60), ..new_link("https://example.com/b") };
+        assert!(local.create(&alias).unwrap().expires_at.is_some());
+        drop(local);
+
+        let local = Local::open(&path, "https://u.rl/").unwrap();
+        assert_eq!(local.resolve(&created.code).unwrap(), "https://example.com/a");
+        assert_eq!(local.create(&new_link("https://example.com/a")).unwrap().code, created.code);
+        let mut codes = Vec::new();
+        local
+            .list(&ListFilter::default(), &mut |link| {
+                codes.push(link.code);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(codes.len(), 2);
+        local.delete("docs").unwrap();
+        assert_eq!(local.resolve("docs").unwrap_err().kind(), "not_found");
+    }
+
+    #[test]
+    fn rejects_like_the_server() {
+        let dir = tempfile::tempdir().unwrap();
+        let local = Local::open(&dir.path().join("links.db"), "u.rl").unwrap();
+        let kind = |link: NewLink| local.create(&link).unwrap_err().kind().to_string();
+        assert_eq!(kind(new_link("ftp://example.com/")), "invalid_url");
+        assert_eq!(kind(NewLink { custom_alias: Some("stats".into()), ..new_link("https://example.com/") }), "invalid_alias");
+        local.create(&NewLink { custom_alias: Some("taken".into()), ..new_link("https://example.com/") }).unwrap();
+        assert_eq!(kind(NewLink { custom_alias: Some("taken".into()), ..new_link("https://other.example/") }), "alias_taken");
+        assert_eq!(kind(NewLink { expires_at: Some("2001-01-01T00:00:00Z".into()), ..new_link("https://example.com/") }), "bad_request");
+        assert_eq!(local.stats("taken").unwrap_err().exit_code(), crate::error::EXIT_INVALID);
+    }
+}
@@ -1,26 +1,420 @@
-use quicli::prelude::*;
-use structopt::StructOpt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
 
-const CONN_ADDR: &str = "127.0.0.1:3002";
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use log::{debug, info};
+use serde::Serialize;
 
-#[derive(Debug, StructOpt)]
+mod client;
+mod error;
+mod local;
+mod output;
+mod verbosity;
+
+use crate::client::{Client, Created, LinkInfo, Links, ListFilter, NewLink, Stats};
+use crate::error::{CliError, Result};
+use crate::local::Local;
+use crate::output::Format;
+use crate::verbosity::Verbosity;
+
+const EXIT_STATUS: &str = "EXIT STATUS:
+    0  success
+    1  local failure, e.g. an unwritable file, or an incomplete import
+    2  invalid input, or the server refused the request (4xx)
+    3  the server could not be reached
+    4  the server failed (5xx) or sent an unreadable answer
+    5  the server's rate limit refused the request; try again later";
+
+/// Command line client for the hyperurl shortener
+#[derive(Debug, Parser)]
+#[command(version, after_help = EXIT_STATUS)]
 struct Cli {
-    #[structopt(long = "url", short = "u")]
-    url: String,
+    /// Server to talk to, e.g. https://u.rl
+    #[arg(long, short, env = "SHORTEN_SERVER", default_value = "http://127.0.0.1:3002", global = true)]
+    server: String,
+
+    /// Work on this store file instead of a server, creating it if needed;
+    /// short URLs still point at --server
+    #[arg(long, value_name = "FILE", env = "SHORTEN_LOCAL", global = true)]
+    local: Option<PathBuf>,
+
+    /// API key, sent as a bearer token
+    #[arg(long, env = "SHORTEN_API_KEY", hide_env_values = true, global = true)]
+    api_key: Option<String>,
+
+    /// Result format
+    #[arg(long, short, value_enum, default_value_t = Format::Text, global = true)]
+    output: Format,
 
-    #[structopt(flatten)]
+    #[command(flatten)]
     verbosity: Verbosity,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Shorten a URL, or every URL in a file
+    Create {
+        #[arg(required_unless_present = "from_file")]
+        url: Option<String>,
+        /// Ask for this code instead of a generated one
+        #[arg(long, short, conflicts_with = "from_file")]
+        alias: Option<String>,
+        /// Let the link expire after this many seconds
+        #[arg(long)]
+        ttl: Option<u64>,
+        /// Shorten each line of this file instead, `-` for stdin; results
+        /// come out in input order
+        #[arg(long, short, value_name = "FILE", conflicts_with = "url")]
+        from_file: Option<PathBuf>,
+        /// Requests in flight at once on servers without a bulk endpoint
+        #[arg(long, short, default_value_t = 4, value_parser = clap::value_parser!(u8).range(1..=64))]
+        jobs: u8,
+    },
+    /// Print the URL behind a code
+    Resolve { code: String },
+    /// List your links, or everyone's with an admin key
+    List {
+        /// Only codes starting with this
+        #[arg(long)]
+        prefix: Option<String>,
+        /// Only links of this owner
+        #[arg(long)]
+        owner: Option<String>,
+        /// Stop after this many links
+        #[arg(long, short = 'n')]
+        limit: Option<usize>,
+    },
+    /// Delete a link
+    Delete { code: String },
+    /// Show click statistics for a code
+    Stats { code: String },
+    /// Load `export` output, JSON lines or CSV, keeping codes, owners and
+    /// dates; a server wants an admin key for this
+    Import {
+        /// Read this file instead of stdin
+        input: Option<PathBuf>,
+    },
+    /// Write every link you can see as JSON lines, or CSV with `-o csv`
+    Export {
+        /// Write this file instead of stdout
+        file: Option<PathBuf>,
+    },
+    /// Print a shell completion script
+    Completions { shell: Shell },
+}
+
+fn main() {
+    let args = Cli::parse();
+    args.verbosity.init_logger();
+    let (format, verbosity) = (args.output, args.verbosity);
+    process::exit(run(args).unwrap_or_else(|e| {
+        format.error(&e, verbosity.show_causes());
+        e.exit_code()
+    }));
+}
+
+#[derive(Serialize)]
+struct Resolved<'a> {
+    code: &'a str,
+    short_url: &'a str,
+    url: &'a str,
+}
+
+#[derive(Serialize)]
+struct Deleted<'a> {
+    code: &'a str,
+    deleted: bool,
+}
+
+/// `stats` as CSV: a `total` row, then one row per day and per referrer.
+#[derive(Serialize)]
+struct StatRow<'a> {
+    code: &'a str,
+    kind: &'static str,
+    key: &'a str,
+    clicks: u64,
+}
+
+/// Result of one input line of `create --from-file` or `import`,
+/// successful or not.
+#[derive(Serialize)]
+struct Outcome {
+    line: usize,
+    code: Option<String>,
+    short_url: Option<String>,
+    url: Option<String>,
+    error: Option<String>,
+    message: Option<String>,
+}
+
+impl Outcome {
+    fn created(line: usize, link: &Created) -> Self {
+        Outcome {
+            line,
+            code: Some(link.code.clone()),
+            short_url: Some(link.short_url.clone()),
+            url: Some(link.url.clone()),
+            error: None,
+            message: None,
+        }
+    }
+
+    fn imported(line: usize, link: &LinkInfo) -> Self {
+        Outcome {
+            line,
+            code: Some(link.code.clone()),
+            short_url: None,
+            url: Some(link.url.clone()),
+            error: None,
+            message: None,
+        }
+    }
+
+    fn failed(line: usize, url: Option<String>, e: &CliError) -> Self {
+        Outcome {
+            line,
+            code: None,
+            short_url: None,
+            url,
+            error: Some(e.kind().to_string()),
+            message: Some(e.message()),
+        }
+    }
+}
+
+/// Runs the command and returns the exit status.
+fn run(args: Cli) -> Result<i32> {
+    if let Command::Completions { shell } = args.command {
+        // Rendered first: the generator panics if stdout goes away.
+        let mut script = Vec::new();
+        clap_complete::generate(shell, &mut Cli::command(), "shorten", &mut script);
+        io::stdout().write_all(&script)?;
+        return Ok(0);
+    }
+    let links: Box<dyn Links> = match args.local {
+        Some(ref path) => Box::new(Local::open(path, &args.server)?),
+        None => Box::new(Client::new(&args.server, args.api_key)?),
+    };
+    let format = args.output;
+
+    match args.command {
+        Command::Create { from_file: Some(path), ttl, jobs, .. } => {
+            return create_batch(&*links, open(&path)?, ttl, jobs.into(), format);
+        }
+        Command::Create { url, alias, ttl, .. } => {
+            let url = url.expect("clap requires a url without --from-file");
+            let link = NewLink { url, custom_alias: alias, ttl, ..NewLink::default() };
+            let created = links.create(&link)?;
+            debug!("code {} created at {}", created.code, created.created_at);
+            if let Some(ref at) = created.expires_at {
+                info!("expires at {}", at);
+            }
+            let mut out = format.one();
+            out.push(&created, &created.short_url)?;
+            out.finish()?;
+        }
+        Command::Resolve { code } => {
+            let url = links.resolve(&code)?;
+            let mut out = format.one();
+            let short_url = links.short_url(&code);
+            out.push(&Resolved { code: &code, short_url: &short_url, url: &url }, &url)?;
+            out.finish()?;
+        }
+        Command::List { prefix, owner, limit } => {
+            let mut out = format.many();
+            links.list(&ListFilter { owner, prefix, limit }, &mut |link| {
+                let text = format!("{}\t{}\t{}", link.code, link.short_url, link.url);
+                out.push(&link, &text)
+            })?;
+            out.finish()?;
+        }
+        Command::Delete { code } => {
+            links.delete(&code)?;
+            let mut out = format.one();
+            out.push(&Deleted { code: &code, deleted: true }, &format_args!("deleted {}", code))?;
+            out.finish()?;
+        }
+        Command::Stats { code } => print_stats(format, &links.stats(&code)?)?,
+        Command::Import { input } => {
+            let from = open(input.as_deref().unwrap_or(Path::new("-")))?;
+            return import(&*links, from, format);
+        }
+        Command::Export { file } => {
+            let to: Box<dyn Write> = match file {
+                Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+                None => Box::new(io::stdout()),
+            };
+            let mut out = format.export(to);
+            links.list(&ListFilter::default(), &mut |link| out.push(&link, &""))?;
+            info!("exported {} links", out.finish()?);
+        }
+        Command::Completions { .. } => unreachable!("handled above"),
+    }
+    Ok(0)
 }
 
-fn main() -> CliResult {
-    let args = Cli::from_args();
-    println!("Shortening: {}", args.url);
-    let client = reqwest::Client::new();
-    let mut res = client
-        .post(&format!("http://{}/shorten", CONN_ADDR))
-        .body(args.url)
-        .send()?;
-    let a: String = res.text().unwrap();
-    println!("http://{}", a);
+fn print_stats(format: Format, stats: &Stats) -> Result<()> {
+    if format == Format::Json {
+        let mut out = format.one();
+        out.push(stats, &"")?;
+        out.finish()?;
+        return Ok(());
+    }
+
+    let row = |kind, key, clicks| StatRow { code: &stats.code, kind, key, clicks };
+    let mut out = format.many();
+    out.push(&row("total", "", stats.total_clicks), &format_args!("{}: {} clicks", stats.code, stats.total_clicks))?;
+    for day in &stats.daily {
+        out.push(&row("day", &day.date, day.clicks), &format_args!("  {}  {}", day.date, day.clicks))?;
+    }
+    for referrer in &stats.top_referrers {
+        out.push(
+            &row("referrer", &referrer.referrer, referrer.clicks),
+            &format_args!("  {}  {}", referrer.referrer, referrer.clicks),
+        )?;
+    }
+    out.finish()?;
     Ok(())
 }
+
+/// `-` is stdin.
+fn open(path: &Path) -> Result<Box<dyn BufRead>> {
+    if path == Path::new("-") {
+        return Ok(Box::new(BufReader::new(io::stdin())));
+    }
+    let file = File::open(path).map_err(|e| CliError::Invalid(format!("{}: {}", path.display(), e)))?;
+    Ok(Box::new(BufReader::new(file)))
+}
+
+/// Shortens one URL per line, skipping blank lines and `#` comments. Every
+/// URL gets a result row, failed or not, in input order; the exit status
+/// is that of the first failure.
+fn create_batch(links: &dyn Links, input: Box<dyn BufRead>, ttl: Option<u64>, jobs: usize, format: Format) -> Result<i32> {
+    let (mut lines, mut batch) = (Vec::new(), Vec::new());
+    for (n, line) in input.lines().enumerate() {
+        let line = line?;
+        let url = line.trim();
+        if url.is_empty() || url.starts_with('#') {
+            continue;
+        }
+        lines.push(n + 1);
+        batch.push(NewLink { url: url.to_string(), ttl, ..NewLink::default() });
+    }
+
+    let mut out = format.many();
+    let (mut failed, mut status) = (0, 0);
+    links.create_many(&batch, jobs, &mut |i, result| match result {
+        Ok(created) => {
+            let text = format!("{}\t{}", created.short_url, batch[i].url);
+            out.push(&Outcome::created(lines[i], &created), &text)
+        }
+        Err(e) => {
+            failed += 1;
+            if status == 0 {
+                status = e.exit_code();
+            }
+            let text = format!("error\t{}\t{}", batch[i].url, e);
+            out.push(&Outcome::failed(lines[i], Some(batch[i].url.clone()), &e), &text)
+        }
+    })?;
+    out.finish()?;
+    info!("shortened {}, failed {}", batch.len() - failed, failed);
+    Ok(status)
+}
+
+/// Loads exported links, JSON lines or CSV, keeping their codes, owners
+/// and dates. Problems are reported per line and do not stop the rest;
+/// the exit status is that of the first failure.
+fn import(links: &dyn Links, mut input: Box<dyn BufRead>, format: Format) -> Result<i32> {
+    let mut text = String::new();
+    input.read_to_string(&mut text)?;
+    let rows = read_links(&text);
+    let batch: Vec<LinkInfo> = rows.iter().filter_map(|(_, row)| row.as_ref().ok().cloned()).collect();
+    let mut results = Vec::with_capacity(batch.len());
+    links.import(&batch, &mut |_, result| {
+        results.push(result);
+        Ok(())
+    })?;
+
+    let mut out = format.many();
+    let (mut imported, mut failed, mut status) = (0, 0, 0);
+    let mut results = results.into_iter();
+    for (n, row) in rows {
+        match row.and_then(|link| results.next().expect("a result per link").map(|()| link)) {
+            Ok(link) => {
+                imported += 1;
+                out.push(&Outcome::imported(n, &link), &format_args!("{}\t{}", link.code, link.url))?;
+            }
+            Err(e) => {
+                failed += 1;
+                if status == 0 {
+                    status = e.exit_code();
+                }
+                if format == Format::Text {
+                    eprintln!("line {}: {}", n, e);
+                } else {
+                    out.push(&Outcome::failed(n, None, &e), &"")?;
+                }
+            }
+        }
+    }
+    out.finish()?;
+    info!("imported {}, failed {}", imported, failed);
+    Ok(status)
+}
+
+/// `export` output by line number: JSON lines, or CSV with a header when
+/// the first line is not a JSON object.
+fn read_links(text: &str) -> Vec<(usize, Result<LinkInfo>)> {
+    let first = text.lines().find(|line| !line.trim().is_empty());
+    if first.is_none_or(|line| line.trim_start().starts_with('{')) {
+        return text
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(n, line)| (n + 1, serde_json::from_str(line).map_err(CliError::from)))
+            .collect();
+    }
+
+    let mut reader = csv::Reader::from_reader(text.as_bytes());
+    let headers = match reader.headers() {
+        Ok(headers) => headers.clone(),
+        Err(e) => return vec![(1, Err(e.into()))],
+    };
+    let (mut rows, mut record) = (Vec::new(), csv::StringRecord::new());
+    loop {
+        let line = |at: Option<&csv::Position>| at.map_or(0, |p| p.line() as usize);
+        let row = match reader.read_record(&mut record) {
+            Ok(false) => return rows,
+            Ok(true) => (line(record.position()), record.deserialize(Some(&headers)).map_err(CliError::from)),
+            Err(e) => (line(e.position()), Err(e.into())),
+        };
+        rows.push(row);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cli_is_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn global_flags_go_anywhere() {
+        let cli = Cli::try_parse_from(["shorten", "list", "-o", "json", "-s", "u.rl", "-vv"]).unwrap();
+        assert_eq!(cli.output, Format::Json);
+        assert_eq!(cli.server, "u.rl");
+        assert!(cli.verbosity.show_causes());
+        assert!(Cli::try_parse_from(["shorten", "-o", "yaml", "list"]).is_err());
+        assert!(Cli::try_parse_from(["shorten", "-q", "-v", "list"]).is_err());
+    }
+}
@@ -0,0 +1,128 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+
+use serde::Serialize;
+
+use crate::error::{CliError, Result};
+
+/// What `--output` asks for.
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Format {
+    /// Plain lines; errors go to stderr
+    Text,
+    /// JSON; errors too, as `{"error", "message", "status"}` on stdout
+    Json,
+    /// CSV under a header row; errors go to stderr as plain text
+    Csv,
+}
+
+/// Error as printed with `--output json`.
+#[derive(Serialize)]
+struct ErrorRecord<'a> {
+    error: &'a str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+}
+
+impl Format {
+    /// Writer for a command with a single result: one JSON object, or one
+    /// CSV row under its header.
+    pub fn one(self) -> Rows {
+        let out: Box<dyn Write> = Box::new(io::stdout());
+        Rows::new(match self {
+            Format::Text => Sink::Text(out),
+            Format::Json => Sink::JsonLines(out),
+            Format::Csv => Sink::Csv(Box::new(csv::Writer::from_writer(out))),
+        })
+    }
+
+    /// Writer for a command listing results; JSON becomes one array.
+    pub fn many(self) -> Rows {
+        let out: Box<dyn Write> = Box::new(io::stdout());
+        Rows::new(match self {
+            Format::Text => Sink::Text(out),
+            Format::Json => Sink::JsonArray(out, Vec::new()),
+            Format::Csv => Sink::Csv(Box::new(csv::Writer::from_writer(out))),
+        })
+    }
+
+    /// Writer for `export`: JSON lines that `import` reads back, or CSV.
+    pub fn export(self, out: Box<dyn Write>) -> Rows {
+        Rows::new(match self {
+            Format::Csv => Sink::Csv(Box::new(csv::Writer::from_writer(out))),
+            _ => Sink::JsonLines(out),
+        })
+    }
+
+    /// Reports a failed command: on stdout as JSON with `--output json`,
+    /// on stderr otherwise. With `causes` the underlying errors follow on
+    /// stderr, e.g. the refused connection behind a network error.
+    pub fn error(self, err: &CliError, causes: bool) {
+        if self == Format::Json {
+            let record = ErrorRecord { error: err.kind(), message: err.message(), status: err.status() };
+            println!("{}", serde_json::to_string(&record).expect("error records serialize"));
+        } else {
+            eprintln!("shorten: {}", err);
+        }
+        let mut cause = err.source();
+        while let (true, Some(e)) = (causes, cause) {
+            eprintln!("  caused by: {}", e);
+            cause = e.source();
+        }
+    }
+}
+
+enum Sink {
+    Text(Box<dyn Write>),
+    JsonLines(Box<dyn Write>),
+    /// Held back until `finish` so a failure halfway never leaves a
+    /// truncated array behind.
+    JsonArray(Box<dyn Write>, Vec<String>),
+    Csv(Box<csv::Writer<Box<dyn Write>>>),
+}
+
+/// Results of one command, written in the chosen format.
+pub struct Rows {
+    sink: Sink,
+    count: usize,
+}
+
+impl Rows {
+    fn new(sink: Sink) -> Self {
+        Rows { sink, count: 0 }
+    }
+
+    /// Writes `item`, or just `text` as a line in text mode.
+    pub fn push<T: Serialize>(&mut self, item: &T, text: &dyn fmt::Display) -> Result<()> {
+        match self.sink {
+            Sink::Text(ref mut out) => writeln!(out, "{}", text)?,
+            Sink::JsonLines(ref mut out) => {
+                serde_json::to_writer(&mut *out, item)?;
+                writeln!(out)?;
+            }
+            Sink::JsonArray(_, ref mut items) => items.push(serde_json::to_string(item)?),
+            Sink::Csv(ref mut out) => out.serialize(item)?,
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Flushes everything written and returns the number of items.
+    pub fn finish(self) -> Result<usize> {
+        match self.sink {
+            Sink::Text(mut out) | Sink::JsonLines(mut out) => out.flush()?,
+            Sink::JsonArray(mut out, items) if items.is_empty() => {
+                writeln!(out, "[]")?;
+                out.flush()?;
+            }
+            Sink::JsonArray(mut out, items) => {
+                writeln!(out, "[\n  {}\n]", items.join(",\n  "))?;
+                out.flush()?;
+            }
+            Sink::Csv(mut out) => out.flush()?,
+        }
+        Ok(self.count)
+    }
+}
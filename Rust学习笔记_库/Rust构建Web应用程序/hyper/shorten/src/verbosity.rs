@@ -0,0 +1,40 @@
+use log::LevelFilter;
+
+/// `-v`/`-q`, shared by every subcommand.
+#[derive(Debug, Clone, Copy, clap::Args)]
+pub struct Verbosity {
+    /// Say more: -v shows progress and error causes, -vv each request
+    #[arg(long, short, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Print nothing but results and the final error
+    #[arg(long, short, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+}
+
+impl Verbosity {
+    pub fn level(&self) -> LevelFilter {
+        if self.quiet {
+            return LevelFilter::Off;
+        }
+        match self.verbose {
+            0 => LevelFilter::Warn,
+            1 => LevelFilter::Info,
+            2 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    }
+
+    /// Whether failures should list their underlying causes.
+    pub fn show_causes(&self) -> bool {
+        self.verbose > 0
+    }
+
+    /// Logs this crate at the chosen level; `RUST_LOG` still governs the
+    /// libraries underneath.
+    pub fn init_logger(&self) {
+        env_logger::Builder::from_default_env()
+            .filter_module("shorten", self.level())
+            .format_timestamp(None)
+            .init();
+    }
+}
@@ -0,0 +1,411 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net;
+use std::process::{Command, Output, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use serde_json::{json, Value};
+
+use hyperurl::auth::ApiKey;
+use hyperurl::config::Config;
+use hyperurl::server::serve;
+use hyperurl::service::App;
+use hyperurl::shortener::CodeScheme;
+use hyperurl::store::{FileStore, MemoryStore};
+
+const ALICE: &str = "alice-secret-key-0001";
+const ADMIN: &str = "ops-secret-key-000001";
+
+/// Starts a hyperurl server, left running for the rest of the test
+/// process, and returns its address.
+fn spawn_with(config: Config) -> String {
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = Arc::new(App::new(config, Arc::new(MemoryStore::new())));
+    thread::spawn(move || {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+            serve(listener, app, std::future::pending()).await
+        });
+    });
+    addr.to_string()
+}
+
+/// Stands in for a server that is not hyperurl: answers each request,
+/// given its request line and body, with a status and a body, one request
+/// per connection. A 429 asks to retry after a second.
+fn stub<F>(reply: F) -> String
+where
+    F: Fn(&str, &str) -> (u16, String) + Send + 'static,
+{
+    let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = BufReader::new(stream.unwrap());
+            let (mut head, mut line) = (String::new(), String::new());
+            stream.read_line(&mut head).unwrap();
+            let mut length = 0;
+            while stream.read_line(&mut line).unwrap() > 2 {
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+                line.clear();
+            }
+            let mut body = vec![0; length];
+            stream.read_exact(&mut body).unwrap();
+            let (status, body) = reply(head.trim_end(), &String::from_utf8(body).unwrap());
+            let mut stream = stream.into_inner();
+            write!(
+                stream,
+                "HTTP/1.1 {} Stubbed\r\nContent-Length: {}\r\nConnection: close\r\n{}\r\n{}",
+                status,
+                body.len(),
+                if status == 429 { "Retry-After: 1\r\n" } else { "" },
+                body
+            )
+            .unwrap();
+        }
+    });
+    addr.to_string()
+}
+
+fn spawn_keyed(codes: CodeScheme) -> String {
+    let key = |owner: &str, key: &str| ApiKey { key: key.into(), owner: owner.into(), admin: owner == "ops" };
+    spawn_with(Config { api_keys: vec![key("alice", ALICE), key("ops", ADMIN)], codes, ..Config::default() })
+}
+
+/// Runs `shorten` against `server`, untouched by the caller's environment.
+fn shorten(server: &str, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_shorten"))
+        .args(["--server", server])
+        .args(args)
+        .env_remove("SHORTEN_LOCAL")
+        .env_remove("SHORTEN_API_KEY")
+        .env_remove("RUST_LOG")
+        .output()
+        .unwrap()
+}
+
+fn shorten_with_stdin(server: &str, args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_shorten"))
+        .args(["--server", server])
+        .args(args)
+        .env_remove("SHORTEN_LOCAL")
+        .env_remove("SHORTEN_API_KEY")
+        .env_remove("RUST_LOG")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(out: &Output) -> String {
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    String::from_utf8(out.stdout.clone()).unwrap()
+}
+
+/// Every link an admin sees, as `list -o json` prints them.
+fn listed(server: &str) -> Vec<Value> {
+    let out = stdout(&shorten(server, &["--api-key", ADMIN, "list", "-o", "json"]));
+    serde_json::from_str::<Value>(&out).unwrap().as_array().unwrap().clone()
+}
+
+#[test]
+fn create_resolve_list_delete() {
+    let server = spawn_keyed(CodeScheme::Hash);
+    let short_url = stdout(&shorten(&server, &["--api-key", ALICE, "create", "https://Example.com/a"]));
+    let code = short_url.trim().rsplit('/').next().unwrap().to_string();
+    assert_eq!(short_url, format!("https://u.rl/{}\n", code));
+
+    assert_eq!(stdout(&shorten(&server, &["resolve", &code])), "https://example.com/a\n");
+    let list = stdout(&shorten(&server, &["--api-key", ALICE, "list"]));
+    assert_eq!(list, format!("{}\t{}\thttps://example.com/a\n", code, short_url.trim()));
+    assert_eq!(stdout(&shorten(&server, &["--api-key", ALICE, "delete", &code])), format!("deleted {}\n", code));
+    assert_eq!(shorten(&server, &["resolve", &code]).status.code(), Some(2));
+}
+
+#[test]
+fn import_keeps_codes_and_metadata() {
+    let from = spawn_keyed(CodeScheme::Counter);
+    stdout(&shorten(&from, &["--api-key", ALICE, "create", "https://example.com/a"]));
+    stdout(&shorten(&from, &["--api-key", ADMIN, "create", "https://example.com/b"]));
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("links.csv");
+    stdout(&shorten(&from, &["--api-key", ADMIN, "-o", "csv", "export", file.to_str().unwrap()]));
+
+    let to = spawn_keyed(CodeScheme::Hash);
+    let out = stdout(&shorten(&to, &["--api-key", ADMIN, "import", file.to_str().unwrap()]));
+    assert_eq!(out.lines().count(), 2, "{}", out);
+    assert_eq!(listed(&to), listed(&from));
+    assert_eq!(listed(&to)[0]["owner"], "alice");
+
+    // Links that have expired are kept as they were too, as are codes
+    // shorter than any alias may be, which older counter servers handed
+    // out; and JSON lines are read as well.
+    let expired = r#"{"code":"gone","url":"https://example.com/c","created_at":"2020-01-01T00:00:00Z","expires_at":"2020-01-02T00:00:00Z"}"#;
+    let short = r#"{"code":"7","url":"https://example.com/d","created_at":"2020-01-01T00:00:00Z"}"#;
+    fs::write(&file, format!("{}\n{}\n", expired, short)).unwrap();
+    stdout(&shorten(&to, &["--api-key", ADMIN, "import", file.to_str().unwrap()]));
+    let gone = listed(&to).into_iter().find(|link| link["code"] == "gone").unwrap();
+    assert_eq!(gone["expires_at"], "2020-01-02T00:00:00Z");
+    assert_eq!(stdout(&shorten(&to, &["resolve", "7"])), "https://example.com/d\n");
+}
+
+#[test]
+fn import_reports_each_bad_line() {
+    let server = spawn_keyed(CodeScheme::Hash);
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("links.jsonl");
+    let line = |code: &str, url: &str| {
+        format!("{{\"code\":\"{}\",\"url\":\"{}\",\"created_at\":\"2020-01-01T00:00:00Z\"}}\n", code, url)
+    };
+    let input = [
+        line("taken", "https://example.com/a"),
+        "not json\n\n".to_string(),
+        line("stats", "https://example.com/b"),
+        line("taken", "https://example.com/other"),
+    ];
+    fs::write(&file, input.concat()).unwrap();
+    let file = file.to_str().unwrap();
+
+    let refused = shorten(&server, &["--api-key", ALICE, "import", file]);
+    assert_eq!(refused.status.code(), Some(2));
+    assert!(listed(&server).is_empty());
+
+    let out = shorten(&server, &["--api-key", ADMIN, "-o", "json", "import", file]);
+    assert_eq!(out.status.code(), Some(2));
+    let outcomes: Vec<Value> = serde_json::from_slice(&out.stdout).unwrap();
+    let lines: Vec<_> = outcomes.iter().map(|o| (o["line"].as_u64().unwrap(), o["error"].clone())).collect();
+    assert_eq!(
+        lines,
+        [
+            (1, Value::Null),
+            (2, "invalid".into()),
+            (4, "invalid_link".into()),
+            (5, "alias_taken".into()),
+        ]
+    );
+    assert_eq!(listed(&server).len(), 1);
+}
+
+#[test]
+fn every_output_format_reports_the_stored_url() {
+    let server = spawn_with(Config::default());
+    let out = stdout(&shorten(&server, &["create", "https://Example.com:443/a"]));
+    let code = out.trim().rsplit('/').next().unwrap().to_string();
+    assert_eq!(out, format!("https://u.rl/{}\n", code));
+
+    let json: Value = serde_json::from_str(&stdout(&shorten(&server, &["-o", "json", "create", "https://Example.com:443/a"]))).unwrap();
+    assert_eq!(json["code"], code.as_str());
+    assert_eq!(json["url"], "https://example.com/a");
+    assert_eq!(json["expires_at"], Value::Null);
+
+    let csv = stdout(&shorten(&server, &["-o", "csv", "create", "https://Example.com:443/a"]));
+    let mut rows = csv.lines();
+    assert_eq!(rows.next(), Some("code,short_url,url,created_at,expires_at"));
+    assert!(rows.next().unwrap().starts_with(&format!("{},https://u.rl/{},https://example.com/a,", code, code)));
+
+    let resolved = stdout(&shorten(&server, &["-o", "csv", "resolve", &code]));
+    let expected = format!("code,short_url,url\n{},http://{}/{},https://example.com/a\n", code, server, code);
+    assert_eq!(resolved, expected);
+
+    // A batch, and a store file, report the same URL.
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("urls.txt");
+    fs::write(&file, "https://Example.com:443/a\n").unwrap();
+    let batch = stdout(&shorten(&server, &["-o", "json", "create", "--from-file", file.to_str().unwrap()]));
+    assert_eq!(serde_json::from_str::<Value>(&batch).unwrap()[0]["url"], "https://example.com/a");
+    let store = dir.path().join("links.db");
+    let local = stdout(&shorten(&server, &["--local", store.to_str().unwrap(), "-o", "json", "create", "https://Example.com:443/a"]));
+    assert_eq!(serde_json::from_str::<Value>(&local).unwrap()["url"], "https://example.com/a");
+}
+
+#[test]
+fn exit_statuses() {
+    let server = spawn_with(Config::default());
+    let status = |server: &str, args: &[&str]| shorten(server, args).status.code().unwrap();
+
+    let dir = tempfile::tempdir().unwrap();
+    let unwritable = dir.path().join("missing").join("links.jsonl");
+    assert_eq!(status(&server, &["export", unwritable.to_str().unwrap()]), 1);
+
+    assert_eq!(status(&server, &["resolve", "nope"]), 2);
+    assert_eq!(status(&server, &["create", "ftp://example.com/"]), 2);
+    let refused = shorten(&server, &["-o", "json", "resolve", "nope"]);
+    let error: Value = serde_json::from_slice(&refused.stdout).unwrap();
+    assert_eq!((error["error"].as_str(), error["status"].as_u64()), (Some("not_found"), Some(404)));
+
+    let closed = net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    assert_eq!(status(&closed, &["resolve", "abc"]), 3);
+
+    let failing = stub(|_, _| (500, "oops".to_string()));
+    assert_eq!(status(&failing, &["create", "https://example.com/"]), 4);
+    let garbled = stub(|_, _| (200, "<html>".to_string()));
+    assert_eq!(status(&garbled, &["create", "https://example.com/"]), 4);
+}
+
+#[test]
+fn codes_are_sent_as_one_path_segment() {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let heads = seen.clone();
+    let server = stub(move |head, _| {
+        heads.lock().unwrap().push(head.to_string());
+        (404, json!({ "error": "not_found", "message": "no such link" }).to_string())
+    });
+    let prefixed = format!("{}/short/", server);
+    for command in ["resolve", "delete", "stats"] {
+        assert_eq!(shorten(&prefixed, &[command, "../a?b#c%d"]).status.code(), Some(2));
+    }
+    assert_eq!(
+        *seen.lock().unwrap(),
+        [
+            "GET /short/..%2Fa%3Fb%23c%25d HTTP/1.1",
+            "DELETE /short/api/links/..%2Fa%3Fb%23c%25d HTTP/1.1",
+            "GET /short/..%2Fa%3Fb%23c%25d/stats HTTP/1.1",
+        ]
+    );
+}
+
+/// What a stub says to a created link, with the last path segment as code.
+fn created(url: &str) -> Value {
+    let code = url.rsplit('/').next().unwrap();
+    json!({ "code": code, "short_url": format!("https://u.rl/{}", code), "url": url, "created_at": "2024-01-01T00:00:00Z" })
+}
+
+/// (line, url, error) of each row of a batch's JSON output.
+fn rows(out: &Output) -> Vec<(u64, String, Option<String>)> {
+    let rows: Vec<Value> = serde_json::from_slice(&out.stdout).unwrap();
+    rows.iter()
+        .map(|row| {
+            let error = row["error"].as_str().map(str::to_string);
+            (row["line"].as_u64().unwrap(), row["url"].as_str().unwrap().to_string(), error)
+        })
+        .collect()
+}
+
+#[test]
+fn batches_keep_input_order_from_a_file_or_stdin() {
+    let server = spawn_with(Config::default());
+    let input = "# links\nhttps://example.com/1\n\nftp://example.com/\nhttps://example.com/2\nhttps://example.com/3\n";
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("urls.txt");
+    fs::write(&file, input).unwrap();
+
+    let from_file = shorten(&server, &["-o", "json", "create", "--from-file", file.to_str().unwrap()]);
+    let from_stdin = shorten_with_stdin(&server, &["-o", "json", "create", "--from-file", "-"], input);
+    assert_eq!(from_file.status.code(), Some(2));
+    assert_eq!(rows(&from_file), rows(&from_stdin));
+    assert_eq!(
+        rows(&from_file),
+        [
+            (2, "https://example.com/1".into(), None),
+            (4, "ftp://example.com/".into(), Some("invalid_url".into())),
+            (5, "https://example.com/2".into(), None),
+            (6, "https://example.com/3".into(), None),
+        ]
+    );
+}
+
+#[test]
+fn batches_fall_back_to_single_creates() {
+    let urls: Vec<String> = (1..=7).map(|n| format!("https://example.com/{}", n)).collect();
+    for status in [404, 405] {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = seen.clone();
+        let server = stub(move |head, body| {
+            log.lock().unwrap().push(head.to_string());
+            if head.starts_with("POST /api/bulk") {
+                return (status, json!({ "error": "not_found", "message": "no such short link" }).to_string());
+            }
+            let req: Value = serde_json::from_str(body).unwrap();
+            (201, created(req["url"].as_str().unwrap()).to_string())
+        });
+
+        let out = shorten_with_stdin(&server, &["-o", "json", "create", "--from-file", "-", "-j", "3"], &urls.join("\n"));
+        let got: Vec<_> = rows(&out).into_iter().map(|(_, url, error)| (url, error)).collect();
+        assert_eq!(got, urls.iter().map(|url| (url.clone(), None)).collect::<Vec<_>>(), "{}", status);
+        let seen = seen.lock().unwrap();
+        assert!(seen[0].starts_with("POST /api/bulk"));
+        assert_eq!(seen[1..].iter().filter(|head| head.starts_with("POST /shorten")).count(), urls.len());
+    }
+}
+
+#[test]
+fn rate_limited_links_are_sent_again() {
+    let bodies = Arc::new(Mutex::new(Vec::new()));
+    let log = bodies.clone();
+    let server = stub(move |_, body| {
+        let items: Vec<Value> = serde_json::from_str(body).unwrap();
+        let first = log.lock().unwrap().is_empty();
+        log.lock().unwrap().push(items.len());
+        let results: Vec<Value> = items
+            .iter()
+            .enumerate()
+            .map(|(index, item)| match (first, index) {
+                (true, 1) => json!({ "index": 1, "error": "rate_limited", "message": "slow down", "retry_after": 1 }),
+                _ => {
+                    let mut done = created(item["url"].as_str().unwrap());
+                    done["index"] = index.into();
+                    done
+                }
+            })
+            .collect();
+        (200, json!({ "created": 0, "existing": 0, "failed": 0, "results": results }).to_string())
+    });
+    let out = shorten_with_stdin(&server, &["-o", "json", "create", "--from-file", "-"], "https://a.example/x\nhttps://a.example/y\n");
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(rows(&out).iter().map(|(_, url, _)| url.as_str()).collect::<Vec<_>>(), ["https://a.example/x", "https://a.example/y"]);
+    assert_eq!(*bodies.lock().unwrap(), [2, 1]);
+
+    // Single creates honour the header the same way.
+    let calls = Arc::new(Mutex::new(0));
+    let count = calls.clone();
+    let server = stub(move |head, body| {
+        if head.starts_with("POST /api/bulk") {
+            return (404, String::new());
+        }
+        let mut calls = count.lock().unwrap();
+        *calls += 1;
+        if *calls == 1 {
+            return (429, json!({ "error": "rate_limited", "message": "slow down" }).to_string());
+        }
+        let req: Value = serde_json::from_str(body).unwrap();
+        (201, created(req["url"].as_str().unwrap()).to_string())
+    });
+    let out = shorten_with_stdin(&server, &["create", "--from-file", "-"], "https://a.example/x\n");
+    assert_eq!(stdout(&out), "https://u.rl/x\thttps://a.example/x\n");
+    assert_eq!(*calls.lock().unwrap(), 2);
+
+    // Waits too long to sit out get an exit status of their own.
+    let server = stub(|_, _| {
+        let limited = json!({ "index": 0, "error": "rate_limited", "message": "slow down", "retry_after": 3600 });
+        (200, json!({ "created": 0, "existing": 0, "failed": 1, "results": [limited] }).to_string())
+    });
+    let out = shorten_with_stdin(&server, &["-o", "json", "create", "--from-file", "-"], "https://a.example/x\n");
+    assert_eq!(out.status.code(), Some(5));
+    assert_eq!(rows(&out)[0].2.as_deref(), Some("rate_limited"));
+}
+
+#[test]
+fn local_store_in_use_is_refused() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("links.db");
+    let held = FileStore::open(&path).unwrap();
+
+    let out = shorten("u.rl", &["--local", path.to_str().unwrap(), "create", "https://example.com/"]);
+    assert_eq!(out.status.code(), Some(1));
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("is in use by another process"), "{}", stderr);
+    drop(held);
+
+    stdout(&shorten("u.rl", &["--local", path.to_str().unwrap(), "create", "https://example.com/"]));
+}